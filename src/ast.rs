@@ -0,0 +1,188 @@
+use std::path::Path;
+
+use crate::tree::write_json_string;
+use crate::{Directive, Line, include_arg};
+
+/// A `#if`/`#ifdef`/`#ifndef` chain, from its opening line to its `#endif`.
+struct Conditional {
+    line: usize,
+    end: usize,
+    branches: Vec<Branch>,
+    else_seen: bool,
+}
+
+/// One branch of a chain: its directive line up to the line before the next
+/// branch or the `#endif`.
+struct Branch {
+    directive: String,
+    line: usize,
+    end: usize,
+    children: Vec<Node>,
+}
+
+enum Node {
+    Conditional(Conditional),
+    Include {
+        line: usize,
+        kind: &'static str,
+        path: String,
+    },
+}
+
+/// Builds the directive structure of `lines` as JSON for `--emit-ast`.
+/// Nothing is evaluated, so every branch is listed and include paths are
+/// given as written.
+pub(crate) fn to_json(path: &Path, lines: &[Line]) -> Result<String, String> {
+    let error = |line_no: usize, message: &str| {
+        format!("{}:{line_no}: invalid directive structure: {message}", path.display())
+    };
+    let mut root = Vec::new();
+    // Chains still waiting for their `#endif`, innermost last.
+    let mut open: Vec<Conditional> = Vec::new();
+    for (idx, line) in lines.iter().enumerate() {
+        let line_no = idx + 1;
+        let Some(directive) = &line.directive else {
+            continue;
+        };
+        let text = line.text.trim().to_string();
+        let include = |kind, path: &str| Node::Include {
+            line: line_no,
+            kind,
+            path: path.to_string(),
+        };
+        match directive {
+            Directive::If(_)
+            | Directive::Ifeq(_)
+            | Directive::Ifneq(_)
+            | Directive::Ifdef(_)
+            | Directive::Ifndef(_) => {
+                open.push(Conditional {
+                    line: line_no,
+                    end: line_no,
+                    branches: vec![Branch {
+                        directive: text,
+                        line: line_no,
+                        end: line_no,
+                        children: Vec::new(),
+                    }],
+                    else_seen: false,
+                });
+            }
+            Directive::Elif(_) | Directive::Elifdef(_) | Directive::Elifndef(_) | Directive::Else => {
+                let name = match directive {
+                    Directive::Elif(_) => "#elif",
+                    Directive::Elifdef(_) => "#elifdef",
+                    Directive::Elifndef(_) => "#elifndef",
+                    _ => "#else",
+                };
+                let Some(chain) = open.last_mut() else {
+                    let message = format!("{name} without matching #if/#ifdef/#ifndef");
+                    return Err(error(line_no, &message));
+                };
+                if chain.else_seen {
+                    return Err(match directive {
+                        Directive::Else => error(line_no, "duplicate #else"),
+                        _ => error(line_no, &format!("{name} after #else")),
+                    });
+                }
+                chain.else_seen = matches!(directive, Directive::Else);
+                if let Some(branch) = chain.branches.last_mut() {
+                    branch.end = line_no - 1;
+                }
+                chain.branches.push(Branch {
+                    directive: text,
+                    line: line_no,
+                    end: line_no,
+                    children: Vec::new(),
+                });
+            }
+            Directive::Endif => {
+                let Some(mut chain) = open.pop() else {
+                    return Err(error(line_no, "#endif without matching #if/#ifdef/#ifndef"));
+                };
+                if let Some(branch) = chain.branches.last_mut() {
+                    branch.end = line_no - 1;
+                }
+                chain.end = line_no;
+                body(&mut open, &mut root).push(Node::Conditional(chain));
+            }
+            Directive::Include { args, .. } => {
+                if let Some(path) = include_arg(args) {
+                    body(&mut open, &mut root).push(include("include", path));
+                }
+            }
+            Directive::IncludeRaw(args) => {
+                if let Some(path) = include_arg(args) {
+                    body(&mut open, &mut root).push(include("include_raw", path));
+                }
+            }
+            Directive::Use(args) => {
+                if let Some(path) = include_arg(args) {
+                    body(&mut open, &mut root).push(include("use", path));
+                }
+            }
+            Directive::DefineInclude(Ok((_, file))) => {
+                if let Some(path) = include_arg(file) {
+                    body(&mut open, &mut root).push(include("define-include", path));
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(chain) = open.last() {
+        return Err(error(chain.line, "missing #endif"));
+    }
+
+    let mut out = String::from("{\"file\":");
+    write_json_string(&mut out, &path.display().to_string());
+    out.push_str(",\"children\":");
+    write_nodes(&mut out, &root);
+    out.push('}');
+    Ok(out)
+}
+
+/// Where the next node goes: the current branch of the innermost open
+/// chain, or the top level.
+fn body<'a>(open: &'a mut [Conditional], root: &'a mut Vec<Node>) -> &'a mut Vec<Node> {
+    match open.last_mut().and_then(|chain| chain.branches.last_mut()) {
+        Some(branch) => &mut branch.children,
+        None => root,
+    }
+}
+
+fn write_nodes(out: &mut String, nodes: &[Node]) {
+    out.push('[');
+    for (i, node) in nodes.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        match node {
+            Node::Conditional(chain) => {
+                out.push_str(&format!(
+                    "{{\"type\":\"conditional\",\"line\":{},\"end\":{},\"branches\":[",
+                    chain.line, chain.end
+                ));
+                for (j, branch) in chain.branches.iter().enumerate() {
+                    if j > 0 {
+                        out.push(',');
+                    }
+                    out.push_str("{\"directive\":");
+                    write_json_string(out, &branch.directive);
+                    out.push_str(&format!(
+                        ",\"line\":{},\"end\":{},\"children\":",
+                        branch.line, branch.end
+                    ));
+                    write_nodes(out, &branch.children);
+                    out.push('}');
+                }
+                out.push_str("]}");
+            }
+            Node::Include { line, kind, path } => {
+                out.push_str(&format!("{{\"type\":\"{kind}\",\"line\":{line},\"path\":"));
+                write_json_string(out, path);
+                out.push('}');
+            }
+        }
+    }
+    out.push(']');
+}
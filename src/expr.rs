@@ -0,0 +1,765 @@
+use std::env;
+
+use crate::{Defs, Error, ErrorKind};
+
+/// A parsed `#if` condition.
+#[derive(Debug, Clone)]
+pub(crate) enum Cond {
+    Or(Box<Cond>, Box<Cond>),
+    And(Box<Cond>, Box<Cond>),
+    Not(Box<Cond>),
+    Eq(Value, Value),
+    Ne(Value, Value),
+    /// `~=`: equal ignoring ASCII case.
+    EqIgnoreCase(Value, Value),
+    Compare(Value, Relation, Value),
+    Between(Value, Value, Value),
+    ContainsWord(Value, Value),
+    /// `VALUE in (A, B, ...)`: equal to any item, as with `==`.
+    In(Value, Vec<Value>),
+    Truthy(Value),
+}
+
+/// A numeric relational operator.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Relation {
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+/// An integer arithmetic operator other than `+`, which also concatenates.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Arith {
+    Sub,
+    Mul,
+    Div,
+}
+
+/// A parsed value expression, evaluated to a string.
+#[derive(Debug, Clone)]
+pub(crate) enum Value {
+    Var(String),
+    Literal(String),
+    Sum(Box<Value>, Box<Value>),
+    Arith(Box<Value>, Arith, Box<Value>),
+    /// Unary `-VALUE`.
+    Neg(Box<Value>),
+    Env(Box<Value>),
+    Len(Box<Value>),
+    DefineCount,
+    AnyDefined(Vec<String>),
+    Defined(String),
+    /// `COND ? THEN : ELSE`.
+    Ternary(Box<Cond>, Box<Value>, Box<Value>),
+}
+
+impl Cond {
+    pub(crate) fn parse(expr: &str) -> Result<Self, Error> {
+        let tokens = tokenize(expr)?;
+        let mut parser = Parser { tokens: &tokens, pos: 0 };
+        let cond = parser.parse_ternary()?;
+        parser.expect_end()?;
+        Ok(cond)
+    }
+
+    /// Evaluates the condition. `&&` and `||` short-circuit: the right
+    /// operand is only evaluated when the left one does not decide the
+    /// result, so `defined(N) && N > 3` never looks at an undefined `N`.
+    pub(crate) fn eval(&self, defs: &Defs) -> Result<bool, Error> {
+        Ok(match self {
+            Self::Or(left, right) => left.eval(defs)? || right.eval(defs)?,
+            Self::And(left, right) => left.eval(defs)? && right.eval(defs)?,
+            Self::Not(inner) => !inner.eval(defs)?,
+            Self::Eq(left, right) => values_equal(&left.eval(defs)?, &right.eval(defs)?),
+            Self::Ne(left, right) => !values_equal(&left.eval(defs)?, &right.eval(defs)?),
+            Self::EqIgnoreCase(left, right) => {
+                left.eval(defs)?.eq_ignore_ascii_case(&right.eval(defs)?)
+            }
+            Self::Compare(left, relation, right) => {
+                let left = left.eval(defs)?;
+                let right = right.eval(defs)?;
+                let (Some(a), Some(b)) = (integer(left.trim()), integer(right.trim())) else {
+                    return Err(invalid(format!(
+                        "invalid expression: relational operator requires numbers, got '{left}' and '{right}'"
+                    )));
+                };
+                match relation {
+                    Relation::Lt => a < b,
+                    Relation::Gt => a > b,
+                    Relation::Le => a <= b,
+                    Relation::Ge => a >= b,
+                }
+            }
+            Self::Between(value, low, high) => {
+                let value = parse_number(&value.eval(defs)?, "between")?;
+                parse_number(&low.eval(defs)?, "between")? <= value
+                    && value <= parse_number(&high.eval(defs)?, "between")?
+            }
+            Self::ContainsWord(list, word) => {
+                let word = word.eval(defs)?;
+                list.eval(defs)?.split_whitespace().any(|w| w == word)
+            }
+            Self::In(value, items) => {
+                let value = value.eval(defs)?;
+                let mut found = false;
+                for item in items {
+                    found |= values_equal(&item.eval(defs)?, &value);
+                }
+                found
+            }
+            Self::Truthy(Value::Var(name)) => defs.is_truthy(name),
+            Self::Truthy(value) => truthy(&value.eval(defs)?),
+        })
+    }
+
+    /// The names in the first comparison (`==`, `!=` or `~=`) whose operands
+    /// are both variables that are currently undefined. Such a comparison
+    /// sees `""` on both sides, so it is always equal.
+    pub(crate) fn undefined_comparison<'a>(&'a self, defs: &Defs) -> Option<(&'a str, &'a str)> {
+        match self {
+            Self::Or(left, right) | Self::And(left, right) => left
+                .undefined_comparison(defs)
+                .or_else(|| right.undefined_comparison(defs)),
+            Self::Not(inner) => inner.undefined_comparison(defs),
+            Self::Eq(left, right) | Self::Ne(left, right) | Self::EqIgnoreCase(left, right) => {
+                match (left, right) {
+                    (Value::Var(a), Value::Var(b)) if !defs.is_defined(a) && !defs.is_defined(b) => {
+                        Some((a, b))
+                    }
+                    _ => left
+                        .undefined_comparison(defs)
+                        .or_else(|| right.undefined_comparison(defs)),
+                }
+            }
+            Self::Compare(left, _, right) | Self::ContainsWord(left, right) => left
+                .undefined_comparison(defs)
+                .or_else(|| right.undefined_comparison(defs)),
+            Self::Between(value, low, high) => [value, low, high]
+                .into_iter()
+                .find_map(|value| value.undefined_comparison(defs)),
+            Self::In(value, items) => std::iter::once(value)
+                .chain(items)
+                .find_map(|value| value.undefined_comparison(defs)),
+            Self::Truthy(value) => value.undefined_comparison(defs),
+        }
+    }
+}
+
+impl Value {
+    /// [`Cond::undefined_comparison`] for the conditions of ternaries
+    /// within this value.
+    fn undefined_comparison<'a>(&'a self, defs: &Defs) -> Option<(&'a str, &'a str)> {
+        match self {
+            Self::Sum(left, right) | Self::Arith(left, _, right) => left
+                .undefined_comparison(defs)
+                .or_else(|| right.undefined_comparison(defs)),
+            Self::Env(inner) | Self::Len(inner) | Self::Neg(inner) => {
+                inner.undefined_comparison(defs)
+            }
+            Self::Ternary(cond, then, otherwise) => cond
+                .undefined_comparison(defs)
+                .or_else(|| then.undefined_comparison(defs))
+                .or_else(|| otherwise.undefined_comparison(defs)),
+            Self::Var(_)
+            | Self::Literal(_)
+            | Self::DefineCount
+            | Self::AnyDefined(_)
+            | Self::Defined(_) => None,
+        }
+    }
+
+    pub(crate) fn parse(expr: &str) -> Result<Self, Error> {
+        let tokens = tokenize(expr)?;
+        let mut parser = Parser { tokens: &tokens, pos: 0 };
+        let value = parser.parse_sum()?;
+        parser.expect_end()?;
+        Ok(value)
+    }
+
+    pub(crate) fn eval(&self, defs: &Defs) -> Result<String, Error> {
+        match self {
+            Self::Var(name) => Ok(defs.get_value(name)),
+            Self::Literal(value) => Ok(value.clone()),
+            // Integer addition when both operands are integers, string
+            // concatenation otherwise (integers are coerced to their decimal form).
+            Self::Sum(left, right) => {
+                let left = left.eval(defs)?;
+                let right = right.eval(defs)?;
+                match (integer(&left), integer(&right)) {
+                    (Some(a), Some(b)) => a
+                        .checked_add(b)
+                        .map(|sum| sum.to_string())
+                        .ok_or_else(|| invalid("invalid expression: integer overflow in '+'")),
+                    _ => Ok(left + &right),
+                }
+            }
+            Self::Arith(left, op, right) => {
+                let symbol = match op {
+                    Arith::Sub => "-",
+                    Arith::Mul => "*",
+                    Arith::Div => "/",
+                };
+                let a = parse_number(&left.eval(defs)?, symbol)?;
+                let b = parse_number(&right.eval(defs)?, symbol)?;
+                let result = match op {
+                    Arith::Sub => a.checked_sub(b),
+                    Arith::Mul => a.checked_mul(b),
+                    Arith::Div if b == 0 => {
+                        return Err(invalid("invalid expression: division by zero"));
+                    }
+                    Arith::Div => a.checked_div(b),
+                };
+                result.map(|value| value.to_string()).ok_or_else(|| {
+                    invalid(format!("invalid expression: integer overflow in '{symbol}'"))
+                })
+            }
+            Self::Neg(value) => parse_number(&value.eval(defs)?, "-")?
+                .checked_neg()
+                .map(|value| value.to_string())
+                .ok_or_else(|| invalid("invalid expression: integer overflow in '-'")),
+            Self::Env(name) => Ok(env::var(name.eval(defs)?).unwrap_or_default()),
+            Self::Len(value) => Ok(value.eval(defs)?.chars().count().to_string()),
+            Self::DefineCount => Ok(defs.defined_count().to_string()),
+            Self::Defined(name) => Ok(if defs.is_defined(name) { "1" } else { "0" }.to_string()),
+            Self::AnyDefined(names) => {
+                let any = names.iter().any(|name| defs.is_defined(name));
+                Ok(if any { "1" } else { "0" }.to_string())
+            }
+            // Only the selected branch is evaluated.
+            Self::Ternary(cond, then, otherwise) => {
+                if cond.eval(defs)? {
+                    then.eval(defs)
+                } else {
+                    otherwise.eval(defs)
+                }
+            }
+        }
+    }
+}
+
+/// Surrounding whitespace is ignored, so `" "` is false and `" no "` too.
+pub(crate) fn truthy(value: &str) -> bool {
+    let value = value.trim();
+    if value.is_empty() {
+        return false;
+    }
+    let upper = value.to_ascii_uppercase();
+    !(upper == "0" || upper == "F" || upper == "FALSE" || upper == "NO")
+}
+
+/// Every problem with an expression, in parsing or evaluation, is an
+/// [`ErrorKind::Expr`] error.
+fn invalid(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::Expr, message)
+}
+
+fn parse_number(value: &str, op: &str) -> Result<i64, Error> {
+    integer(value.trim()).ok_or_else(|| {
+        invalid(format!("invalid expression: '{op}' expects a number, got '{value}'"))
+    })
+}
+
+/// Parses an integer operand value: decimal, hex (`0xFF`) or binary
+/// (`0b1010`), so a define such as `-DFLAGS=0xFF` is a number too.
+fn integer(value: &str) -> Option<i64> {
+    match radix_digits(value) {
+        Some((digits, radix)) if digits.chars().all(|c| c.is_ascii_alphanumeric()) => {
+            i64::from_str_radix(digits, radix).ok()
+        }
+        Some(_) => None,
+        None => value.parse().ok(),
+    }
+}
+
+/// The digits and radix of a `0x` or `0b` prefixed number.
+fn radix_digits(value: &str) -> Option<(&str, u32)> {
+    match value.get(..2)? {
+        "0x" | "0X" => Some((&value[2..], 16)),
+        "0b" | "0B" => Some((&value[2..], 2)),
+        _ => None,
+    }
+}
+
+/// `==` on evaluated operands: string equality, except that a hex or binary
+/// operand is compared by value, so `0xFF` equals `255`.
+fn values_equal(left: &str, right: &str) -> bool {
+    let (trimmed_left, trimmed_right) = (left.trim(), right.trim());
+    if (radix_digits(trimmed_left).is_some() || radix_digits(trimmed_right).is_some())
+        && let (Some(a), Some(b)) = (integer(trimmed_left), integer(trimmed_right))
+    {
+        return a == b;
+    }
+    left == right
+}
+
+#[derive(Debug, Clone)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(String),
+    And,
+    Or,
+    Eq,
+    Ne,
+    EqIgnoreCase,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Not,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Question,
+    Colon,
+    Comma,
+    LParen,
+    RParen,
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    let chars: Vec<char> = expr.chars().collect();
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        match c {
+            '&' => {
+                if i + 1 < chars.len() && chars[i + 1] == '&' {
+                    tokens.push(Token::And);
+                    i += 2;
+                } else {
+                    return Err(invalid("invalid expression: single '&'"));
+                }
+            }
+            '|' => {
+                if i + 1 < chars.len() && chars[i + 1] == '|' {
+                    tokens.push(Token::Or);
+                    i += 2;
+                } else {
+                    return Err(invalid("invalid expression: single '|'"));
+                }
+            }
+            '=' => {
+                if i + 1 < chars.len() && chars[i + 1] == '=' {
+                    tokens.push(Token::Eq);
+                    i += 2;
+                } else {
+                    return Err(invalid("invalid expression: single '='"));
+                }
+            }
+            '~' => {
+                if i + 1 < chars.len() && chars[i + 1] == '=' {
+                    tokens.push(Token::EqIgnoreCase);
+                    i += 2;
+                } else {
+                    return Err(invalid("invalid expression: single '~'"));
+                }
+            }
+            '!' => {
+                if i + 1 < chars.len() && chars[i + 1] == '=' {
+                    tokens.push(Token::Ne);
+                    i += 2;
+                } else {
+                    tokens.push(Token::Not);
+                    i += 1;
+                }
+            }
+            '<' | '>' => {
+                let or_equal = chars.get(i + 1) == Some(&'=');
+                tokens.push(match (c, or_equal) {
+                    ('<', false) => Token::Lt,
+                    ('<', true) => Token::Le,
+                    (_, false) => Token::Gt,
+                    (_, true) => Token::Ge,
+                });
+                i += if or_equal { 2 } else { 1 };
+            }
+            '+' | '-' | '*' | '/' => {
+                tokens.push(match c {
+                    '+' => Token::Plus,
+                    '-' => Token::Minus,
+                    '*' => Token::Star,
+                    _ => Token::Slash,
+                });
+                i += 1;
+            }
+            '?' => {
+                tokens.push(Token::Question);
+                i += 1;
+            }
+            ':' => {
+                tokens.push(Token::Colon);
+                i += 1;
+            }
+            ',' => {
+                tokens.push(Token::Comma);
+                i += 1;
+            }
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            '"' => {
+                i += 1;
+                let mut s = String::new();
+                while i < chars.len() {
+                    let ch = chars[i];
+                    if ch == '"' {
+                        break;
+                    }
+                    if ch == '\\' && i + 1 < chars.len() {
+                        let next = chars[i + 1];
+                        s.push(next);
+                        i += 2;
+                        continue;
+                    }
+                    s.push(ch);
+                    i += 1;
+                }
+                if i >= chars.len() || chars[i] != '"' {
+                    return Err(invalid("invalid expression: unterminated string"));
+                }
+                i += 1;
+                tokens.push(Token::Str(s));
+            }
+            // `0x1F` and `0b1010` are normalized to their decimal value.
+            '0' if matches!(chars.get(i + 1), Some('x' | 'X' | 'b' | 'B')) => {
+                let radix = if chars[i + 1].eq_ignore_ascii_case(&'x') { 16 } else { 2 };
+                i += 2;
+                let mut digits = String::new();
+                while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                    digits.push(chars[i]);
+                    i += 1;
+                }
+                let value = i64::from_str_radix(&digits, radix)
+                    .map_err(|_| invalid("invalid expression: malformed number"))?;
+                tokens.push(Token::Num(value.to_string()));
+            }
+            c if c.is_ascii_digit() => {
+                let mut s = String::new();
+                s.push(c);
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    s.push(chars[i]);
+                    i += 1;
+                }
+                tokens.push(Token::Num(s));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut s = String::new();
+                s.push(c);
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    s.push(chars[i]);
+                    i += 1;
+                }
+                tokens.push(Token::Ident(s));
+            }
+            _ => return Err(invalid(format!("invalid expression: unexpected char '{c}'"))),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Parses a whole condition. A top-level `COND ? THEN : ELSE` binds
+    /// loosest and is tested for truthiness.
+    fn parse_ternary(&mut self) -> Result<Cond, Error> {
+        let cond = self.parse_or()?;
+        if self.match_token(|t| matches!(t, Token::Question)) {
+            return Ok(Cond::Truthy(self.parse_branches(cond)?));
+        }
+        Ok(cond)
+    }
+
+    /// Parses the `THEN : ELSE` of a ternary whose `?` has been consumed.
+    fn parse_branches(&mut self, cond: Cond) -> Result<Value, Error> {
+        let then = self.parse_sum()?;
+        if !self.match_token(|t| matches!(t, Token::Colon)) {
+            return Err(invalid("invalid expression: expected ':' in '? :'"));
+        }
+        let otherwise = self.parse_sum()?;
+        Ok(Value::Ternary(Box::new(cond), Box::new(then), Box::new(otherwise)))
+    }
+
+    fn parse_or(&mut self) -> Result<Cond, Error> {
+        let mut left = self.parse_and()?;
+        while self.match_token(|t| matches!(t, Token::Or)) {
+            let right = self.parse_and()?;
+            left = Cond::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Cond, Error> {
+        let mut left = self.parse_not()?;
+        while self.match_token(|t| matches!(t, Token::And)) {
+            let right = self.parse_not()?;
+            left = Cond::And(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_not(&mut self) -> Result<Cond, Error> {
+        if self.match_token(|t| matches!(t, Token::Not)) {
+            let v = self.parse_not()?;
+            return Ok(Cond::Not(Box::new(v)));
+        }
+        self.parse_cmp()
+    }
+
+    fn parse_cmp(&mut self) -> Result<Cond, Error> {
+        if matches!(self.tokens.get(self.pos), Some(Token::LParen)) {
+            let start = self.pos;
+            self.pos += 1;
+            let v = self.parse_or()?;
+            if !self.match_token(|t| matches!(t, Token::RParen)) {
+                if !matches!(self.tokens.get(self.pos), Some(Token::Question)) {
+                    return Err(invalid("invalid expression: missing ')'"));
+                }
+            } else if matches!(
+                self.tokens.get(self.pos),
+                None | Some(Token::RParen | Token::And | Token::Or | Token::Question)
+            ) {
+                return Ok(v);
+            }
+            // A parenthesized ternary, or a group followed by an operator as
+            // in `(A + 1) * 2 > 4`, is a value operand; parse it again as one.
+            self.pos = start;
+        }
+        let left = self.parse_sum()?;
+        if self.match_token(|t| matches!(t, Token::Eq)) {
+            let right = self.parse_sum()?;
+            return Ok(Cond::Eq(left, right));
+        }
+        if self.match_token(|t| matches!(t, Token::Ne)) {
+            let right = self.parse_sum()?;
+            return Ok(Cond::Ne(left, right));
+        }
+        if self.match_token(|t| matches!(t, Token::EqIgnoreCase)) {
+            let right = self.parse_sum()?;
+            return Ok(Cond::EqIgnoreCase(left, right));
+        }
+        let relation = match self.tokens.get(self.pos) {
+            Some(Token::Lt) => Some(Relation::Lt),
+            Some(Token::Gt) => Some(Relation::Gt),
+            Some(Token::Le) => Some(Relation::Le),
+            Some(Token::Ge) => Some(Relation::Ge),
+            _ => None,
+        };
+        if let Some(relation) = relation {
+            self.pos += 1;
+            let right = self.parse_sum()?;
+            return Ok(Cond::Compare(left, relation, right));
+        }
+        if self.match_token(|t| matches!(t, Token::Ident(op) if op == "between")) {
+            let low = self.parse_sum()?;
+            if !self.match_token(|t| matches!(t, Token::Ident(op) if op == "and")) {
+                return Err(invalid("invalid expression: expected 'and' after 'between'"));
+            }
+            let high = self.parse_sum()?;
+            return Ok(Cond::Between(left, low, high));
+        }
+        if self.match_token(|t| matches!(t, Token::Ident(op) if op == "contains_word")) {
+            let word = self.parse_sum()?;
+            return Ok(Cond::ContainsWord(left, word));
+        }
+        if self.match_token(|t| matches!(t, Token::Ident(op) if op == "in")) {
+            return Ok(Cond::In(left, self.parse_list()?));
+        }
+        Ok(Cond::Truthy(left))
+    }
+
+    fn parse_sum(&mut self) -> Result<Value, Error> {
+        let mut left = self.parse_product()?;
+        loop {
+            if self.match_token(|t| matches!(t, Token::Plus)) {
+                let right = self.parse_product()?;
+                left = Value::Sum(Box::new(left), Box::new(right));
+            } else if self.match_token(|t| matches!(t, Token::Minus)) {
+                let right = self.parse_product()?;
+                left = Value::Arith(Box::new(left), Arith::Sub, Box::new(right));
+            } else {
+                return Ok(left);
+            }
+        }
+    }
+
+    fn parse_product(&mut self) -> Result<Value, Error> {
+        let mut left = self.parse_value()?;
+        loop {
+            let op = match self.tokens.get(self.pos) {
+                Some(Token::Star) => Arith::Mul,
+                Some(Token::Slash) => Arith::Div,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.parse_value()?;
+            left = Value::Arith(Box::new(left), op, Box::new(right));
+        }
+    }
+
+    /// Parses an operand: a name, literal or call, a `-` negating one, a
+    /// parenthesized sum such as `(A + 1)`, or a parenthesized ternary.
+    fn parse_value(&mut self) -> Result<Value, Error> {
+        if self.match_token(|t| matches!(t, Token::Minus)) {
+            return Ok(Value::Neg(Box::new(self.parse_value()?)));
+        }
+        if self.match_token(|t| matches!(t, Token::LParen)) {
+            let cond = self.parse_or()?;
+            if let Cond::Truthy(value) = &cond
+                && self.match_token(|t| matches!(t, Token::RParen))
+            {
+                return Ok(value.clone());
+            }
+            if !self.match_token(|t| matches!(t, Token::Question)) {
+                return Err(invalid("invalid expression: expected '?' in parenthesized value"));
+            }
+            let value = self.parse_branches(cond)?;
+            if !self.match_token(|t| matches!(t, Token::RParen)) {
+                return Err(invalid("invalid expression: missing ')'"));
+            }
+            return Ok(value);
+        }
+        if let Some(token) = self.tokens.get(self.pos) {
+            let value = match token {
+                Token::Ident(name)
+                    if matches!(self.tokens.get(self.pos + 1), Some(Token::LParen)) =>
+                {
+                    let name = name.clone();
+                    self.pos += 2;
+                    return self.parse_call(&name);
+                }
+                Token::Ident(name) => Value::Var(name.clone()),
+                Token::Str(s) => Value::Literal(s.clone()),
+                Token::Num(n) => Value::Literal(n.clone()),
+                _ => return Err(invalid("invalid expression: expected value")),
+            };
+            self.pos += 1;
+            return Ok(value);
+        }
+        Err(invalid("invalid expression: unexpected end"))
+    }
+
+    /// Parses the parenthesized, comma-separated items after `in`.
+    fn parse_list(&mut self) -> Result<Vec<Value>, Error> {
+        if !self.match_token(|t| matches!(t, Token::LParen)) {
+            return Err(invalid("invalid expression: expected '(' after 'in'"));
+        }
+        let mut items = vec![self.parse_sum()?];
+        while self.match_token(|t| matches!(t, Token::Comma)) {
+            items.push(self.parse_sum()?);
+        }
+        if !self.match_token(|t| matches!(t, Token::RParen)) {
+            return Err(invalid("invalid expression: missing ')' after in (...)"));
+        }
+        Ok(items)
+    }
+
+    /// Parses the comma-separated arguments of a call to the function
+    /// `name`, whose opening parenthesis has already been consumed.
+    fn parse_call(&mut self, name: &str) -> Result<Value, Error> {
+        if name == "defined" {
+            return self.parse_defined();
+        }
+        if name == "any_defined" {
+            return self.parse_any_defined();
+        }
+        let mut args = Vec::new();
+        if !self.match_token(|t| matches!(t, Token::RParen)) {
+            loop {
+                args.push(self.parse_sum()?);
+                if self.match_token(|t| matches!(t, Token::RParen)) {
+                    break;
+                }
+                if !self.match_token(|t| matches!(t, Token::Comma)) {
+                    let message = format!("invalid expression: missing ')' after {name}(...)");
+                    return Err(invalid(message));
+                }
+            }
+        }
+        match (name, args.len()) {
+            ("env", 1) => Ok(Value::Env(Box::new(args.remove(0)))),
+            ("len", 1) => Ok(Value::Len(Box::new(args.remove(0)))),
+            ("define_count", 0) => Ok(Value::DefineCount),
+            ("env" | "len" | "define_count", n) => Err(invalid(format!(
+                "invalid expression: wrong number of arguments ({n}) to {name}(...)"
+            ))),
+            _ => Err(invalid(format!("invalid expression: unknown function '{name}'"))),
+        }
+    }
+
+    /// Parses the `NAME)` of `defined(NAME)`. Unlike other arguments, `NAME`
+    /// is not expanded to its value.
+    fn parse_defined(&mut self) -> Result<Value, Error> {
+        let name = self.parse_name("defined")?;
+        if !self.match_token(|t| matches!(t, Token::RParen)) {
+            return Err(invalid("invalid expression: missing ')' after defined(...)"));
+        }
+        Ok(Value::Defined(name))
+    }
+
+    /// Parses the `A, B, ...)` of `any_defined(A, B, ...)`. As with
+    /// `defined`, the names are not expanded to their values.
+    fn parse_any_defined(&mut self) -> Result<Value, Error> {
+        let mut names = vec![self.parse_name("any_defined")?];
+        while self.match_token(|t| matches!(t, Token::Comma)) {
+            names.push(self.parse_name("any_defined")?);
+        }
+        if !self.match_token(|t| matches!(t, Token::RParen)) {
+            return Err(invalid("invalid expression: missing ')' after any_defined(...)"));
+        }
+        Ok(Value::AnyDefined(names))
+    }
+
+    /// Parses a variable name argument of `func`, bare or quoted.
+    fn parse_name(&mut self, func: &str) -> Result<String, Error> {
+        let name = match self.tokens.get(self.pos) {
+            Some(Token::Ident(name) | Token::Str(name)) => name.clone(),
+            _ => {
+                let message = format!("invalid expression: {func}(...) expects a variable name");
+                return Err(invalid(message));
+            }
+        };
+        self.pos += 1;
+        Ok(name)
+    }
+
+    fn expect_end(&self) -> Result<(), Error> {
+        if self.pos != self.tokens.len() {
+            let message = format!("invalid expression: unexpected token at position {}", self.pos);
+            return Err(invalid(message));
+        }
+        Ok(())
+    }
+
+    fn match_token<F>(&mut self, pred: F) -> bool
+    where
+        F: Fn(&Token) -> bool,
+    {
+        if let Some(tok) = self.tokens.get(self.pos)
+            && pred(tok)
+        {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+}
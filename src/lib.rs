@@ -0,0 +1,2391 @@
+//! textpp: a small text preprocessor for Markdown and other plain-text files.
+//!
+//! [`process_file`] and [`process_content`] run the preprocessor over a file or
+//! an in-memory string. [`Template`] parses a source once so it can be rendered
+//! repeatedly with different [`Defs`].
+
+mod ast;
+mod expr;
+mod tree;
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use expr::{Cond, Value};
+use tree::TreeRecorder;
+
+/// The set of defined variables.
+#[derive(Debug, Clone, Default)]
+pub struct Defs {
+    values: HashMap<String, String>,
+    defined: HashMap<String, bool>,
+    lists: HashMap<String, Vec<String>>,
+}
+
+impl Defs {
+    pub fn new() -> Self {
+        Self {
+            values: HashMap::new(),
+            defined: HashMap::new(),
+            lists: HashMap::new(),
+        }
+    }
+
+    /// Defines `key` with `value`, or makes it undefined for `None`.
+    pub fn set_defined(&mut self, key: &str, value: Option<String>) {
+        self.lists.remove(key);
+        match value {
+            Some(v) => {
+                self.values.insert(key.to_string(), v);
+                self.defined.insert(key.to_string(), true);
+            }
+            None => {
+                self.values.remove(key);
+                self.defined.insert(key.to_string(), false);
+            }
+        }
+    }
+
+    /// Builds a set from command-line arguments: `-DKEY[=VALUE]` as for
+    /// [`apply_define`], and `-U KEY`, `-UKEY` or `--undef KEY`, which apply
+    /// after every define. The other arguments are returned in order.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> (Self, Vec<String>) {
+        let mut defs = Self::new();
+        let mut undefs = Vec::new();
+        let mut rest = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match DefineArg::parse(&arg, &mut args) {
+                Some(DefineArg::Define(spec)) => apply_define(&mut defs, &spec),
+                Some(DefineArg::Undef(key)) => undefs.push(key),
+                Some(DefineArg::Empty) => {}
+                None => rest.push(arg),
+            }
+        }
+        for key in &undefs {
+            defs.undefine(key);
+        }
+        (defs, rest)
+    }
+
+    /// Defines `key` with `value`, like `#define KEY VALUE`.
+    pub fn define(&mut self, key: &str, value: impl Into<String>) {
+        self.set_defined(key, Some(value.into()));
+    }
+
+    /// Defines `key` as a block of text, such as a file's contents or
+    /// `#capture` output, dropping its final line terminator so the value
+    /// expands cleanly inline. Empty text leaves `key` undefined.
+    pub fn define_text(&mut self, key: &str, mut text: String) {
+        if text.ends_with('\n') {
+            text.pop();
+            if text.ends_with('\r') {
+                text.pop();
+            }
+        }
+        self.set_defined(key, (!text.is_empty()).then_some(text));
+    }
+
+    /// Defines `key` as a list, like `#list KEY = A, B`. Its plain value is
+    /// the items joined with a space.
+    pub fn define_list(&mut self, key: &str, items: Vec<String>) {
+        self.set_defined(key, Some(items.join(" ")));
+        self.lists.insert(key.to_string(), items);
+    }
+
+    /// The items of `key` if it is currently defined as a list.
+    pub fn list(&self, key: &str) -> Option<&[String]> {
+        self.lists.get(key).map(Vec::as_slice)
+    }
+
+    /// Makes `key` undefined, like `#undef KEY`.
+    pub fn undefine(&mut self, key: &str) {
+        self.set_defined(key, None);
+    }
+
+    pub fn is_defined(&self, key: &str) -> bool {
+        self.defined.get(key).copied().unwrap_or(false)
+    }
+
+    /// Every variable that has been defined or undefined, sorted by name,
+    /// with its value, or `None` if it is currently undefined.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<String>)> + '_ {
+        let mut keys: Vec<&String> = self.defined.keys().collect();
+        keys.sort();
+        keys.into_iter()
+            .map(|key| (key.as_str(), self.is_defined(key).then(|| self.get_value(key))))
+    }
+
+    /// Number of currently defined variables.
+    pub(crate) fn defined_count(&self) -> usize {
+        self.defined.values().filter(|defined| **defined).count()
+    }
+
+    /// The value of `key`, or an empty string if it is undefined.
+    pub fn get_value(&self, key: &str) -> String {
+        if self.is_defined(key) {
+            self.values.get(key).cloned().unwrap_or_else(|| "TRUE".to_string())
+        } else {
+            String::new()
+        }
+    }
+
+    /// Whether `key` counts as true in `#if KEY`: defined with a value
+    /// other than empty, `0`, `F`, `FALSE` or `NO` (case-insensitive).
+    /// `KEY=FALSE` is therefore defined for `#ifdef` but false here.
+    pub fn is_truthy(&self, key: &str) -> bool {
+        expr::truthy(&self.get_value(key))
+    }
+
+    /// The value of `key`, or `None` if it is undefined. Unlike
+    /// [`Defs::get_value`], a variable defined as empty yields `Some("")`.
+    pub fn lookup(&self, key: &str) -> Option<&str> {
+        self.is_defined(key)
+            .then(|| self.values.get(key).map_or("TRUE", String::as_str))
+    }
+}
+
+/// A `-D` or `-U` command-line argument, as [`Defs::from_args`] reads them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DefineArg {
+    /// `-DKEY[=VALUE]`, with the `KEY[=VALUE]` to pass to [`apply_define`].
+    Define(String),
+    /// `-U KEY`, `-UKEY` or `--undef KEY`.
+    Undef(String),
+    /// A bare `-D`, or `-U` with no key after it, which do nothing.
+    Empty,
+}
+
+impl DefineArg {
+    /// Reads `arg` as a define argument, taking the key of `-U KEY` or
+    /// `--undef KEY` from `rest`. Any other argument gives `None`.
+    pub fn parse(arg: &str, rest: &mut impl Iterator<Item = String>) -> Option<Self> {
+        if let Some(spec) = arg.strip_prefix("-D") {
+            return Some(match spec {
+                "" => Self::Empty,
+                spec => Self::Define(spec.to_string()),
+            });
+        }
+        if arg == "-U" || arg == "--undef" {
+            return Some(rest.next().map_or(Self::Empty, Self::Undef));
+        }
+        arg.strip_prefix("-U").map(|key| Self::Undef(key.to_string()))
+    }
+}
+
+/// Line terminator policy for the output.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LineEndings {
+    #[default]
+    Lf,
+    Crlf,
+    Preserve,
+    Error,
+}
+
+impl LineEndings {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "lf" => Some(Self::Lf),
+            "crlf" => Some(Self::Crlf),
+            "preserve" => Some(Self::Preserve),
+            "error" => Some(Self::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Character encoding of the input file and everything it includes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum InputEncoding {
+    #[default]
+    Utf8,
+    /// ISO-8859-1: each byte is the code point of the same value.
+    Latin1,
+}
+
+impl InputEncoding {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "utf8" | "utf-8" => Some(Self::Utf8),
+            "latin1" | "latin-1" | "iso-8859-1" => Some(Self::Latin1),
+            _ => None,
+        }
+    }
+
+    /// Decodes `bytes` to UTF-8 text. Only invalid UTF-8 can fail, with a
+    /// message pointing at `--input-encoding`; every byte sequence is valid
+    /// Latin-1.
+    pub fn decode(self, bytes: Vec<u8>) -> Result<String, String> {
+        match self {
+            Self::Utf8 => String::from_utf8(bytes).map_err(|e| {
+                let e = e.utf8_error();
+                format!("not valid UTF-8 ({e}); set --input-encoding to read other encodings")
+            }),
+            Self::Latin1 => Ok(bytes.into_iter().map(char::from).collect()),
+        }
+    }
+}
+
+/// Default for [`Options::max_include_depth`].
+pub const DEFAULT_MAX_INCLUDE_DEPTH: usize = 64;
+
+/// How many times `--expand-recursive` expands a line before giving up.
+pub const MAX_EXPANSION_PASSES: usize = 16;
+
+/// Settings that affect how input is processed.
+#[derive(Debug)]
+pub struct Options {
+    pub line_endings: LineEndings,
+    /// Text added at the start of every output line.
+    pub line_prefix: String,
+    /// Text added at the end of every output line, before its terminator.
+    pub line_suffix: String,
+    /// Fail once the output grows past this many bytes, counted over every
+    /// file processed with these options, as one budget for the whole run.
+    pub max_output_bytes: Option<usize>,
+    pub input_encoding: InputEncoding,
+    pub preserve_shebang: bool,
+    pub roundtrippable: bool,
+    /// Write each directive line to the output as a `<!-- textpp: ... -->`
+    /// comment, including directives in inactive branches.
+    pub preserve_directives: bool,
+    pub quiet: bool,
+    /// Directories searched, in order, for an `#include` that does not
+    /// exist relative to the including file.
+    pub include_dirs: Vec<PathBuf>,
+    /// Directory that relative includes resolve against in every file,
+    /// instead of the including file's own directory.
+    pub include_base: Option<PathBuf>,
+    /// How deeply includes may nest; the top-level file is depth 0.
+    pub max_include_depth: usize,
+    /// Warn when an include resolves to an existing but empty file.
+    pub warn_empty_includes: bool,
+    /// Splice an included file that does not end in a newline into the
+    /// output as is, so the line after the `#include` continues its last
+    /// line, instead of ending that line.
+    pub no_newline_on_include: bool,
+    /// Make an include that cannot be read an error instead of skipping it.
+    pub strict_includes: bool,
+    /// Record the include tree and branch decisions for [`Options::tree_json`].
+    pub emit_tree: bool,
+    /// Re-expand `$$VAR$$` in substituted values until the line stops
+    /// changing, up to [`MAX_EXPANSION_PASSES`] passes.
+    pub expand_recursive: bool,
+    /// Log each directive, and the branch it selected, to stderr.
+    pub trace: bool,
+    /// Recognize directives preceded by spaces or tabs, not only at column 0.
+    pub allow_indented_directives: bool,
+    /// What starts a directive line, `#` by default. `//#` makes `//#if X`
+    /// a directive and leaves `#if X` as text.
+    pub directive_prefix: String,
+    /// Fail on a line whose word right after `#` is not a directive but is
+    /// close to the name of one, such as `#ifdff`.
+    pub strict_directives: bool,
+    /// Record the files read through includes for [`Options::included_files`].
+    pub list_includes: bool,
+    /// Let `#exec` run commands; without it `#exec` is an error.
+    pub allow_exec: bool,
+    /// Record include read failures for [`Options::take_errors`] and skip
+    /// the include, instead of failing on the first one.
+    pub keep_going: bool,
+    /// Make `==`, `!=` and `~=` in `#if` and `#elif` an error when both
+    /// operands are undefined variables, instead of comparing `""` to `""`.
+    pub undefined_compare_error: bool,
+    /// How undefined variables are handled at the start of the input file.
+    /// `#pragma undefined=default` returns to this policy.
+    pub undefined: UndefinedPolicy,
+    warnings: Cell<usize>,
+    /// Bytes written so far, for `max_output_bytes`.
+    output_bytes: Cell<usize>,
+    tree: TreeRecorder,
+    /// Files that declared `#pragma once` and were processed in this run.
+    once: RefCell<HashSet<PathBuf>>,
+    included: RefCell<Vec<PathBuf>>,
+    errors: RefCell<Vec<String>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            line_endings: LineEndings::default(),
+            line_prefix: String::new(),
+            line_suffix: String::new(),
+            max_output_bytes: None,
+            input_encoding: InputEncoding::default(),
+            preserve_shebang: false,
+            roundtrippable: false,
+            preserve_directives: false,
+            quiet: false,
+            include_dirs: Vec::new(),
+            include_base: None,
+            max_include_depth: DEFAULT_MAX_INCLUDE_DEPTH,
+            warn_empty_includes: false,
+            no_newline_on_include: false,
+            strict_includes: false,
+            emit_tree: false,
+            expand_recursive: false,
+            trace: false,
+            allow_indented_directives: false,
+            directive_prefix: "#".to_string(),
+            strict_directives: false,
+            list_includes: false,
+            allow_exec: false,
+            keep_going: false,
+            undefined_compare_error: false,
+            undefined: UndefinedPolicy::default(),
+            warnings: Cell::new(0),
+            output_bytes: Cell::new(0),
+            tree: TreeRecorder::default(),
+            once: RefCell::default(),
+            included: RefCell::default(),
+            errors: RefCell::default(),
+        }
+    }
+}
+
+impl Options {
+    /// Number of warnings issued so far, including suppressed ones.
+    pub fn warning_count(&self) -> usize {
+        self.warnings.get()
+    }
+
+    /// Reports a warning on stderr unless `--quiet` is set. Warnings are
+    /// counted either way so `--fail-on-warning` still sees them.
+    pub fn warn(&self, message: &str) {
+        self.warnings.set(self.warnings.get() + 1);
+        if !self.quiet {
+            eprintln!("{message}");
+        }
+    }
+
+    /// The last processed top-level file as JSON: its path, the conditional
+    /// branches it evaluated and the files it included, nested in order.
+    /// `None` unless `emit_tree` was set while processing.
+    pub fn tree_json(&self) -> Option<String> {
+        self.tree.to_json()
+    }
+
+    /// The files the last top-level file read through `#include`,
+    /// `#include_raw`, `#use` and `#define-include`, transitively and in the
+    /// order first read. Only includes reached under the defines in effect
+    /// are listed. Empty unless `list_includes` was set while processing.
+    pub fn included_files(&self) -> Vec<PathBuf> {
+        self.included.borrow().clone()
+    }
+
+    /// Adds `path` to [`Options::included_files`] if `list_includes` is set.
+    fn record_include(&self, path: &Path) {
+        let mut included = self.included.borrow_mut();
+        if self.list_includes && !included.iter().any(|p| p == path) {
+            included.push(path.to_path_buf());
+        }
+    }
+
+    /// Returns and clears the errors recorded under `keep_going`, in the
+    /// order they occurred.
+    pub fn take_errors(&self) -> Vec<String> {
+        self.errors.take()
+    }
+}
+
+/// An error raised while processing input or rendering a [`Template`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    message: String,
+    kind: ErrorKind,
+}
+
+/// The class of problem an [`Error`] reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A malformed or failing `#if`, `#elif` or `#eval` expression.
+    Expr,
+    /// A mismatched or unterminated `#if`, `#for`, `#capture` or
+    /// `#require-defined` block.
+    Structure,
+    /// A file that could not be read or written, or a command that could not
+    /// be started.
+    Io,
+    /// A file that includes itself, directly or indirectly.
+    Cycle,
+    /// Anything else, such as invalid directive arguments or `#error`.
+    Other,
+}
+
+impl Error {
+    /// An error of the given `kind`.
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            kind,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// The same error with `at` applied to its message, such as one adding
+    /// the location it was raised at.
+    fn located(self, at: impl FnOnce(String) -> String) -> Self {
+        Self {
+            message: at(self.message),
+            kind: self.kind,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A source parsed once into directives and expressions, ready to be
+/// rendered any number of times.
+///
+/// `#include`d files are read and processed at render time rather than at
+/// compile time, because their paths may depend on the defines in effect.
+#[derive(Debug, Clone)]
+pub struct Template {
+    path: PathBuf,
+    lines: Vec<Line>,
+}
+
+impl Template {
+    /// Parses `source`. Relative `#include` paths resolve against `base_dir`.
+    pub fn compile(source: &str, base_dir: impl AsRef<Path>) -> Self {
+        Self {
+            path: base_dir.as_ref().join("<template>"),
+            lines: compile_lines(source, &Options::default()),
+        }
+    }
+
+    /// Renders the template with a copy of `defs`; directives such as
+    /// `#define` do not affect the caller's set.
+    pub fn render(&self, defs: &Defs) -> Result<String, Error> {
+        let mut defs = defs.clone();
+        let mut out = String::new();
+        let opts = Options::default();
+        let mut includes = vec![self.path.clone()];
+        let undefined = opts.undefined;
+        execute(&self.path, &self.lines, &mut defs, &mut out, &opts, undefined, &mut includes)?;
+        Ok(out)
+    }
+}
+
+/// What `$$VAR$$` does when `VAR` is undefined.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum UndefinedPolicy {
+    /// Expand to an empty string.
+    #[default]
+    Empty,
+    /// Expand to an empty string and warn.
+    Warn,
+    /// Fail.
+    Error,
+}
+
+#[derive(Debug)]
+struct CondFrame {
+    /// Line of the opening `#if`, `#ifdef` or `#ifndef`.
+    line: usize,
+    parent_active: bool,
+    active: bool,
+    /// Whether any branch of the chain so far had a true condition.
+    any_taken: bool,
+    else_seen: bool,
+}
+
+#[derive(Debug)]
+struct LoopFrame {
+    var: String,
+    items: Vec<String>,
+    next: usize,
+    /// Index of the first body line, where each iteration restarts.
+    body_start: usize,
+    /// Conditional stack depth at `#for`; the body must leave it unchanged.
+    cond_depth: usize,
+    parent_active: bool,
+    /// Value of `var` before the loop, restored at `#endfor`.
+    saved: Option<String>,
+    broken: bool,
+}
+
+/// The arguments of `#split TEXT on "SEP" into NAME...`.
+#[derive(Debug, Clone)]
+struct SplitSpec {
+    text: String,
+    separator: String,
+    names: Vec<String>,
+}
+
+impl SplitSpec {
+    fn parse(args: &str) -> Result<Self, Error> {
+        let invalid = || {
+            let message =
+                format!("invalid directive: #split expects TEXT on \"SEP\" into NAME..., got '{args}'");
+            Error::new(ErrorKind::Other, message)
+        };
+        let (head, names) = args.rsplit_once(" into ").ok_or_else(invalid)?;
+        let (text, separator) = head.rsplit_once(" on ").ok_or_else(invalid)?;
+        let separator = separator
+            .trim()
+            .strip_prefix('"')
+            .and_then(|s| s.strip_suffix('"'))
+            .filter(|s| !s.is_empty())
+            .ok_or_else(invalid)?;
+        let text = text.trim();
+        let text = text
+            .strip_prefix('"')
+            .and_then(|t| t.strip_suffix('"'))
+            .unwrap_or(text);
+        let names: Vec<String> = names.split_whitespace().map(str::to_string).collect();
+        if names.is_empty() || !names.iter().all(|name| is_ident(name)) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            text: text.to_string(),
+            separator: separator.to_string(),
+            names,
+        })
+    }
+}
+
+/// A source line with its terminator and, if it is one, the parsed directive.
+#[derive(Debug, Clone)]
+struct Line {
+    text: String,
+    eol: String,
+    directive: Option<Directive>,
+}
+
+/// A parsed directive. Argument errors are kept so they are reported only
+/// when the directive is reached, as if it were parsed on the fly.
+#[derive(Debug, Clone)]
+enum Directive {
+    Include { args: String, align: Option<usize> },
+    IncludeRaw(String),
+    /// `#// ...` or `#; ...`, dropped from the output.
+    Comment,
+    /// A line joined onto the directive before it by a trailing `\`.
+    Continuation,
+    Use(String),
+    Ifdef(String),
+    Ifndef(String),
+    If(Result<Cond, Error>),
+    /// `#ifeq (A, B)`, with its two operands as written.
+    Ifeq(Result<(String, String), Error>),
+    /// `#ifneq (A, B)`, with its two operands as written.
+    Ifneq(Result<(String, String), Error>),
+    DefineInclude(Result<(String, String), Error>),
+    Define(String, String),
+    Eval(Result<(String, Value), Error>),
+    /// `#exec NAME = PROGRAM ARGS...`, split on whitespace.
+    Exec(Result<(String, Vec<String>), Error>),
+    /// `#list NAME = A, B, ...`, split on commas.
+    List(Result<(String, Vec<String>), Error>),
+    Undef(String),
+    RequireDefined,
+    EndRequireDefined,
+    /// `#pragma undefined=POLICY`; `None` restores the policy the file started with.
+    PragmaUndefined(Result<Option<UndefinedPolicy>, Error>),
+    PragmaOnce,
+    Capture(Result<String, Error>),
+    EndCapture,
+    Warning(String),
+    Error(String),
+    Split(Result<SplitSpec, Error>),
+    For(Result<(String, String), Error>),
+    EndFor,
+    Break,
+    Continue,
+    Elif(Result<Cond, Error>),
+    Elifdef(String),
+    Elifndef(String),
+    Else,
+    Endif,
+}
+
+impl Directive {
+    /// Whether this directive opens a branch of a conditional chain.
+    fn is_branch(&self) -> bool {
+        matches!(
+            self,
+            Directive::Ifdef(_)
+                | Directive::Ifndef(_)
+                | Directive::If(_)
+                | Directive::Ifeq(_)
+                | Directive::Ifneq(_)
+                | Directive::Elif(_)
+                | Directive::Elifdef(_)
+                | Directive::Elifndef(_)
+                | Directive::Else
+        )
+    }
+}
+
+/// Applies a `-D` style `KEY[=VALUE]` definition. A bare `KEY` is set to
+/// `TRUE`; an empty `VALUE` makes `KEY` undefined.
+pub fn apply_define(defs: &mut Defs, spec: &str) {
+    if let Some((k, v)) = spec.split_once('=') {
+        if v.is_empty() {
+            defs.set_defined(k, None);
+        } else {
+            defs.set_defined(k, Some(v.to_string()));
+        }
+    } else {
+        defs.set_defined(spec, Some("TRUE".to_string()));
+    }
+}
+
+/// Applies one `KEY=VALUE` line from a defines source. Blank lines and lines
+/// starting with `#` are ignored, whitespace around `KEY` and `VALUE` is
+/// trimmed, and a value wrapped in double quotes is unquoted.
+pub fn apply_define_line(defs: &mut Defs, line: &str) -> Result<(), String> {
+    match parse_define_line(line)? {
+        Some((key, Some(value))) => apply_define(defs, &format!("{key}={value}")),
+        Some((key, None)) => apply_define(defs, key),
+        None => {}
+    }
+    Ok(())
+}
+
+/// Like [`apply_define_line`], for a `--defines-file` or `--defines-stdin`
+/// line: the same format,
+/// except that an empty value (`KEY=` or `KEY=""`) defines `KEY` as empty
+/// instead of making it undefined.
+pub fn apply_defines_file_line(defs: &mut Defs, line: &str) -> Result<(), String> {
+    match parse_define_line(line)? {
+        Some((key, Some(value))) => defs.define(key, value),
+        Some((key, None)) => apply_define(defs, key),
+        None => {}
+    }
+    Ok(())
+}
+
+/// Splits a `KEY=VALUE` line into the key and the unquoted value, if any.
+/// Blank lines and comments give `None`.
+fn parse_define_line(line: &str) -> Result<Option<(&str, Option<&str>)>, String> {
+    let line = line.trim_start();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let (key, value) = match line.split_once('=') {
+        Some((key, value)) => (key.trim(), Some(unquote(value.trim()))),
+        None => (line.trim_end(), None),
+    };
+    if !is_ident(key) {
+        return Err(format!("invalid define '{line}': expected KEY=VALUE"));
+    }
+    Ok(Some((key, value)))
+}
+
+/// Processes the file at `input` and returns the result. `defs` is left
+/// unchanged; directives such as `#define` act on a copy.
+pub fn preprocess(input: &Path, defs: &Defs, opts: &Options) -> Result<String, Error> {
+    let content = read_source(input, opts).map_err(|e| {
+        Error::new(ErrorKind::Io, format!("cannot read {}: {e}", input.display()))
+    })?;
+    let mut out = String::new();
+    process_content(input, &content, &mut defs.clone(), &mut out, opts)?;
+    Ok(out)
+}
+
+/// Processes the file at `path`, appending the result to `out`. Fails if
+/// `path` cannot be read; includes that cannot be read are skipped.
+pub fn process_file(
+    path: &Path,
+    defs: &mut Defs,
+    out: &mut String,
+    opts: &Options,
+) -> Result<(), Error> {
+    wrap_output(out, opts, |out| {
+        process_included(path, defs, out, opts, opts.undefined, &mut Vec::new())
+    })
+}
+
+/// Like [`process_file`], but writes each line to `out` as soon as it is
+/// produced instead of building the whole result in memory. On error, `out`
+/// may already have received part of the output.
+pub fn process_file_to(
+    path: &Path,
+    defs: &mut Defs,
+    out: &mut impl io::Write,
+    opts: &Options,
+) -> Result<(), Error> {
+    wrap_output(&mut WriteSink(out), opts, |out| {
+        process_included(path, defs, out, opts, opts.undefined, &mut Vec::new())
+    })
+}
+
+/// The directive structure of `content` as JSON: nested `#if` / `#ifdef` /
+/// `#ifndef` chains with each branch's directive and line range, and the
+/// includes within them. Nothing is evaluated or included; `path` only
+/// labels the result and errors.
+pub fn directive_ast(path: &Path, content: &str, opts: &Options) -> Result<String, Error> {
+    let lines = compile_lines(content, opts);
+    ast::to_json(path, &lines).map_err(|message| Error::new(ErrorKind::Structure, message))
+}
+
+/// Processes already-loaded `content` as if it were read from `path`, which
+/// is used to resolve relative includes and to label errors.
+pub fn process_content(
+    path: &Path,
+    content: &str,
+    defs: &mut Defs,
+    out: &mut String,
+    opts: &Options,
+) -> Result<(), Error> {
+    let mut includes = vec![fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())];
+    let lines = compile_lines(content, opts);
+    wrap_output(out, opts, |out| {
+        execute(path, &lines, defs, out, opts, opts.undefined, &mut includes)
+    })
+}
+
+/// Like [`process_content`], but streams the result to `out` as
+/// [`process_file_to`] does.
+pub fn process_content_to(
+    path: &Path,
+    content: &str,
+    defs: &mut Defs,
+    out: &mut impl io::Write,
+    opts: &Options,
+) -> Result<(), Error> {
+    let mut includes = vec![fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())];
+    let lines = compile_lines(content, opts);
+    wrap_output(&mut WriteSink(out), opts, |out| {
+        execute(path, &lines, defs, out, opts, opts.undefined, &mut includes)
+    })
+}
+
+/// Destination for processed text: a `String`, or a writer when streaming.
+trait Sink {
+    fn emit(&mut self, text: &str) -> Result<(), Error>;
+}
+
+impl Sink for String {
+    fn emit(&mut self, text: &str) -> Result<(), Error> {
+        self.push_str(text);
+        Ok(())
+    }
+}
+
+struct WriteSink<W>(W);
+
+impl<W: io::Write> Sink for WriteSink<W> {
+    fn emit(&mut self, text: &str) -> Result<(), Error> {
+        self.0
+            .write_all(text.as_bytes())
+            .map_err(|e| Error::new(ErrorKind::Io, format!("cannot write output: {e}")))
+    }
+}
+
+/// Reads the file at `path` as text in `opts.input_encoding`.
+fn read_source(path: &Path, opts: &Options) -> Result<String, String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    opts.input_encoding.decode(bytes)
+}
+
+/// Runs `run` with `out`, failing as soon as the output exceeds
+/// `opts.max_output_bytes`, with lines wrapped by [`wrap_lines`].
+fn wrap_output(
+    out: &mut dyn Sink,
+    opts: &Options,
+    run: impl FnOnce(&mut dyn Sink) -> Result<(), Error>,
+) -> Result<(), Error> {
+    match opts.max_output_bytes {
+        Some(limit) => {
+            let used = &opts.output_bytes;
+            wrap_lines(&mut LimitSink { inner: out, used, limit }, opts, run)
+        }
+        None => wrap_lines(out, opts, run),
+    }
+}
+
+/// Passes text through until `used` reaches `limit` bytes. `used` is shared
+/// by every file of the run.
+struct LimitSink<'a> {
+    inner: &'a mut dyn Sink,
+    used: &'a Cell<usize>,
+    limit: usize,
+}
+
+impl Sink for LimitSink<'_> {
+    fn emit(&mut self, text: &str) -> Result<(), Error> {
+        let used = self.used.get() + text.len();
+        if used > self.limit {
+            return Err(Error::new(ErrorKind::Other, "output size limit exceeded"));
+        }
+        self.used.set(used);
+        self.inner.emit(text)
+    }
+}
+
+/// Runs `run` with `out`, adding `opts.line_prefix` and `opts.line_suffix`
+/// to every line it writes.
+fn wrap_lines(
+    out: &mut dyn Sink,
+    opts: &Options,
+    run: impl FnOnce(&mut dyn Sink) -> Result<(), Error>,
+) -> Result<(), Error> {
+    if opts.line_prefix.is_empty() && opts.line_suffix.is_empty() {
+        return run(out);
+    }
+    let mut sink = LineWrapSink {
+        inner: out,
+        prefix: &opts.line_prefix,
+        suffix: &opts.line_suffix,
+        line_start: true,
+    };
+    run(&mut sink)?;
+    // A last line without a terminator still gets its suffix.
+    if !sink.line_start {
+        sink.inner.emit(sink.suffix)?;
+    }
+    Ok(())
+}
+
+/// Wraps each line passing through it, however the text is split into
+/// `emit` calls, so a multi-line value is wrapped line by line.
+struct LineWrapSink<'a> {
+    inner: &'a mut dyn Sink,
+    prefix: &'a str,
+    suffix: &'a str,
+    line_start: bool,
+}
+
+impl Sink for LineWrapSink<'_> {
+    fn emit(&mut self, text: &str) -> Result<(), Error> {
+        let mut rest = text;
+        while !rest.is_empty() {
+            if self.line_start {
+                self.inner.emit(self.prefix)?;
+                self.line_start = false;
+            }
+            let Some(end) = rest.find('\n') else {
+                return self.inner.emit(rest);
+            };
+            let (line, eol) = match rest[..end].strip_suffix('\r') {
+                Some(line) => (line, "\r\n"),
+                None => (&rest[..end], "\n"),
+            };
+            self.inner.emit(line)?;
+            self.inner.emit(self.suffix)?;
+            self.inner.emit(eol)?;
+            self.line_start = true;
+            rest = &rest[end + 1..];
+        }
+        Ok(())
+    }
+}
+
+/// Processes the file at `path` as part of the include chain `includes`,
+/// the canonical paths of the files currently being processed. An include
+/// that cannot be read is skipped, but the root file (an empty chain) must be
+/// readable.
+fn process_included(
+    path: &Path,
+    defs: &mut Defs,
+    out: &mut dyn Sink,
+    opts: &Options,
+    undefined: UndefinedPolicy,
+    includes: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    let content = match read_source(path, opts) {
+        Ok(c) => c,
+        Err(e) if includes.is_empty() => {
+            let message = format!("cannot read {}: {e}", path.display());
+            return Err(Error::new(ErrorKind::Io, message));
+        }
+        Err(_) => return Ok(()),
+    };
+    if !includes.is_empty() {
+        opts.record_include(path);
+    }
+    includes.push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+    let lines = compile_lines(&content, opts);
+    let result = execute(path, &lines, defs, out, opts, undefined, includes);
+    includes.pop();
+    result
+}
+
+/// Handles an include of `path` that cannot be read: skipped, unless
+/// `strict_includes` makes it an error (recorded instead under `keep_going`).
+fn missing_include(
+    path: &Path,
+    opts: &Options,
+    at: impl Fn(String) -> String,
+) -> Result<(), Error> {
+    if !opts.strict_includes {
+        return Ok(());
+    }
+    let message = at(format!("cannot read include: {}", path.display()));
+    if opts.keep_going {
+        opts.errors.borrow_mut().push(message);
+        return Ok(());
+    }
+    Err(Error::new(ErrorKind::Io, message))
+}
+
+/// Fails if including `path` would nest deeper than allowed, or if `path` is
+/// already on the include chain, which would recurse forever. With
+/// `strict_includes` a missing file fails too. `at` adds the location of the
+/// including directive to messages.
+fn check_include(
+    path: &Path,
+    includes: &[PathBuf],
+    opts: &Options,
+    at: impl Fn(String) -> String,
+) -> Result<(), Error> {
+    let canonical = match fs::canonicalize(path) {
+        Ok(canonical) if canonical.is_file() => canonical,
+        _ => return missing_include(path, opts, at),
+    };
+    if includes.len() > opts.max_include_depth {
+        return Err(Error::new(
+            ErrorKind::Other,
+            at(format!(
+                "include depth limit ({}) exceeded by {}",
+                opts.max_include_depth,
+                path.display()
+            )),
+        ));
+    }
+    if opts.warn_empty_includes && fs::metadata(path).is_ok_and(|meta| meta.len() == 0) {
+        opts.warn(&at(format!("warning: included file '{}' is empty", path.display())));
+    }
+    match includes.iter().position(|p| *p == canonical) {
+        Some(start) => {
+            let chain: Vec<String> = includes[start..]
+                .iter()
+                .chain([&canonical])
+                .map(|p| p.display().to_string())
+                .collect();
+            let message = at(format!("include cycle detected: {}", chain.join(" -> ")));
+            Err(Error::new(ErrorKind::Cycle, message))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Splits `content` into lines and parses the directives, which start with
+/// `opts.directive_prefix`. With `opts.allow_indented_directives`, leading
+/// spaces and tabs before the prefix are skipped; the line text itself keeps
+/// them.
+fn compile_lines(content: &str, opts: &Options) -> Vec<Line> {
+    let parse = |text: &str| {
+        let text = if opts.allow_indented_directives {
+            text.trim_start_matches([' ', '\t'])
+        } else {
+            text
+        };
+        parse_directive(text.strip_prefix(opts.directive_prefix.as_str())?)
+    };
+    let physical = split_lines(content);
+    let mut lines = Vec::with_capacity(physical.len());
+    let mut idx = 0;
+    while idx < physical.len() {
+        let (first, mut eol) = physical[idx];
+        let mut text = first.to_string();
+        let start = idx + 1;
+        idx += 1;
+        // A directive ending in `\` continues on the next line. The joined
+        // lines stay in the list as continuations, so line numbers still
+        // match the source. A `\` on the last line is kept as it is.
+        if parse(first).is_some() {
+            while idx < physical.len() && text.ends_with('\\') {
+                text.pop();
+                text.push_str(physical[idx].0);
+                eol = physical[idx].1;
+                idx += 1;
+            }
+        }
+        lines.push(Line {
+            directive: parse(&text),
+            text,
+            eol: eol.to_string(),
+        });
+        lines.extend(physical[start..idx].iter().map(|(text, _)| Line {
+            text: text.to_string(),
+            eol: String::new(),
+            directive: Some(Directive::Continuation),
+        }));
+    }
+    lines
+}
+
+/// Parses a directive from `rest`, the part of its line after the prefix.
+fn parse_directive(rest: &str) -> Option<Directive> {
+    if rest.starts_with("//") || rest.starts_with(';') {
+        return Some(Directive::Comment);
+    }
+    let trimmed = rest.trim_start();
+    if let Some(args) = directive_args(trimmed, "include_raw") {
+        return Some(Directive::IncludeRaw(args.to_string()));
+    }
+    if let Some(args) = trimmed.strip_prefix("include") {
+        let (args, align) = split_include_align(args);
+        return Some(Directive::Include {
+            args: args.to_string(),
+            align,
+        });
+    }
+    if let Some(args) = directive_args(trimmed, "use") {
+        return Some(Directive::Use(args.to_string()));
+    }
+    if let Some(name) = trimmed.strip_prefix("ifdef") {
+        return Some(Directive::Ifdef(name.trim().to_string()));
+    }
+    if let Some(name) = trimmed.strip_prefix("ifndef") {
+        return Some(Directive::Ifndef(name.trim().to_string()));
+    }
+    if let Some(args) = ifeq_directive_args(trimmed, "ifeq") {
+        return Some(Directive::Ifeq(parse_ifeq_args("ifeq", args)));
+    }
+    if let Some(args) = ifeq_directive_args(trimmed, "ifneq") {
+        return Some(Directive::Ifneq(parse_ifeq_args("ifneq", args)));
+    }
+    if let Some(expr) = trimmed.strip_prefix("if") {
+        return Some(Directive::If(Cond::parse(expr.trim())));
+    }
+    if let Some(args) = directive_args(trimmed, "define-include") {
+        let parsed = args
+            .split_once(char::is_whitespace)
+            .filter(|(name, _)| is_ident(name))
+            .map(|(name, file)| (name.to_string(), file.to_string()))
+            .ok_or_else(|| {
+                let message =
+                    format!("invalid directive: #define-include expects NAME \"path\", got '{args}'");
+                Error::new(ErrorKind::Other, message)
+            });
+        return Some(Directive::DefineInclude(parsed));
+    }
+    if let Some(args) = directive_args(trimmed, "define")
+        && let Some((name, value)) = parse_define_args(args)
+    {
+        return Some(Directive::Define(name, value));
+    }
+    if let Some(args) = directive_args(trimmed, "eval") {
+        let parsed = args
+            .split_once('=')
+            .map(|(name, expr)| (name.trim(), expr.trim()))
+            .filter(|(name, _)| is_ident(name))
+            .ok_or_else(|| {
+                let message = format!("invalid directive: #eval expects NAME = EXPR, got '{args}'");
+                Error::new(ErrorKind::Other, message)
+            })
+            .and_then(|(name, expr)| Ok((name.to_string(), Value::parse(expr)?)));
+        return Some(Directive::Eval(parsed));
+    }
+    if let Some(args) = directive_args(trimmed, "exec") {
+        let parsed = args
+            .split_once('=')
+            .map(|(name, command)| {
+                let command: Vec<String> = command.split_whitespace().map(str::to_string).collect();
+                (name.trim().to_string(), command)
+            })
+            .filter(|(name, command)| is_ident(name) && !command.is_empty())
+            .ok_or_else(|| {
+                let message = format!("invalid directive: #exec expects NAME = COMMAND, got '{args}'");
+                Error::new(ErrorKind::Other, message)
+            });
+        return Some(Directive::Exec(parsed));
+    }
+    if let Some(args) = directive_args(trimmed, "list") {
+        let parsed = args
+            .split_once('=')
+            .map(|(name, items)| {
+                let items: Vec<String> = match items.trim() {
+                    "" => Vec::new(),
+                    items => items.split(',').map(|item| item.trim().to_string()).collect(),
+                };
+                (name.trim().to_string(), items)
+            })
+            .filter(|(name, _)| is_ident(name))
+            .ok_or_else(|| {
+                let message = format!("invalid directive: #list expects NAME = ITEMS, got '{args}'");
+                Error::new(ErrorKind::Other, message)
+            });
+        return Some(Directive::List(parsed));
+    }
+    if let Some(args) = directive_args(trimmed, "undef")
+        && let Some(name) = parse_single_ident(args)
+    {
+        return Some(Directive::Undef(name));
+    }
+    if let Some(args) = directive_args(trimmed, "pragma") {
+        if args.trim() == "once" {
+            return Some(Directive::PragmaOnce);
+        }
+        // Other pragmas, such as `#pragma warning(disable: 4996)`, belong to
+        // whatever reads the output, so they pass through as text.
+        let parsed = match args.split_once('=').map(|(key, value)| (key.trim(), value.trim())) {
+            Some(("undefined", "empty")) => Ok(Some(UndefinedPolicy::Empty)),
+            Some(("undefined", "warn")) => Ok(Some(UndefinedPolicy::Warn)),
+            Some(("undefined", "error")) => Ok(Some(UndefinedPolicy::Error)),
+            Some(("undefined", "default")) => Ok(None),
+            Some(("undefined", value)) => Err(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "invalid directive: #pragma undefined expects error, warn, empty or default, got '{value}'"
+                ),
+            )),
+            _ => return None,
+        };
+        return Some(Directive::PragmaUndefined(parsed));
+    }
+    if directive_args(trimmed, "require-defined").is_some() {
+        return Some(Directive::RequireDefined);
+    }
+    if directive_args(trimmed, "end-require-defined").is_some() {
+        return Some(Directive::EndRequireDefined);
+    }
+    if let Some(args) = directive_args(trimmed, "capture") {
+        let parsed = parse_single_ident(args).ok_or_else(|| {
+            let message = format!("invalid directive: #capture expects a variable name, got '{args}'");
+            Error::new(ErrorKind::Other, message)
+        });
+        return Some(Directive::Capture(parsed));
+    }
+    if directive_args(trimmed, "endcapture").is_some() {
+        return Some(Directive::EndCapture);
+    }
+    if let Some(message) = directive_args(trimmed, "warning") {
+        return Some(Directive::Warning(message.to_string()));
+    }
+    if let Some(message) = directive_args(trimmed, "error") {
+        return Some(Directive::Error(message.to_string()));
+    }
+    if let Some(args) = directive_args(trimmed, "split") {
+        return Some(Directive::Split(SplitSpec::parse(args)));
+    }
+    if let Some(args) = directive_args(trimmed, "for") {
+        let parsed = args
+            .split_once(" in ")
+            .map(|(var, list)| (var.trim(), list))
+            .filter(|(var, _)| is_ident(var))
+            .map(|(var, list)| (var.to_string(), list.to_string()))
+            .ok_or_else(|| {
+                let message = format!("invalid directive: #for expects VAR in ITEMS..., got '{args}'");
+                Error::new(ErrorKind::Other, message)
+            });
+        return Some(Directive::For(parsed));
+    }
+    if directive_args(trimmed, "endfor").is_some() {
+        return Some(Directive::EndFor);
+    }
+    if directive_args(trimmed, "break").is_some() {
+        return Some(Directive::Break);
+    }
+    if directive_args(trimmed, "continue").is_some() {
+        return Some(Directive::Continue);
+    }
+    if let Some(name) = trimmed.strip_prefix("elifdef") {
+        return Some(Directive::Elifdef(name.trim().to_string()));
+    }
+    if let Some(name) = trimmed.strip_prefix("elifndef") {
+        return Some(Directive::Elifndef(name.trim().to_string()));
+    }
+    if let Some(expr) = trimmed.strip_prefix("elif") {
+        return Some(Directive::Elif(Cond::parse(expr.trim())));
+    }
+    if trimmed.starts_with("else") {
+        return Some(Directive::Else);
+    }
+    if trimmed.starts_with("endif") {
+        return Some(Directive::Endif);
+    }
+    None
+}
+
+/// The buffer output currently goes to: the innermost `#capture`, if any.
+fn capture_target<'a>(
+    captures: &'a mut [(String, String, usize)],
+    out: &'a mut dyn Sink,
+) -> &'a mut dyn Sink {
+    match captures.last_mut() {
+        Some((_, buf, _)) => buf,
+        None => out,
+    }
+}
+
+fn execute(
+    path: &Path,
+    lines: &[Line],
+    defs: &mut Defs,
+    out: &mut dyn Sink,
+    opts: &Options,
+    undefined: UndefinedPolicy,
+    includes: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    // A new top-level file starts a new run for `#pragma once`.
+    if includes.len() == 1 {
+        opts.once.borrow_mut().clear();
+        opts.included.borrow_mut().clear();
+    }
+    if !opts.emit_tree {
+        return execute_lines(path, lines, defs, out, opts, undefined, includes);
+    }
+    opts.tree.enter(&path.display().to_string());
+    let result = execute_lines(path, lines, defs, out, opts, undefined, includes);
+    opts.tree.leave();
+    result
+}
+
+fn execute_lines(
+    path: &Path,
+    lines: &[Line],
+    defs: &mut Defs,
+    out: &mut dyn Sink,
+    opts: &Options,
+    undefined: UndefinedPolicy,
+    includes: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    if opts.line_endings == LineEndings::Error {
+        let crlf = lines.iter().any(|line| line.eol == "\r\n");
+        let lf = lines.iter().any(|line| line.eol == "\n");
+        if crlf && lf {
+            let message = format!("{}: mixed line endings (LF and CRLF)", path.display());
+            return Err(Error::new(ErrorKind::Other, message));
+        }
+    }
+    let base_dir = match &opts.include_base {
+        Some(dir) => dir.as_path(),
+        None => path.parent().unwrap_or_else(|| Path::new(".")),
+    };
+    let mut stack: Vec<CondFrame> = Vec::new();
+    let mut current_active = true;
+    // The policy in effect when the file started, for `#pragma undefined=default`.
+    let inherited_undefined = undefined;
+    let mut undefined = undefined;
+    // Line of the open #require-defined and the policy to restore at its end.
+    let mut require_defined_start: Option<(usize, UndefinedPolicy)> = None;
+    // Open #capture blocks: variable name, captured text, and line of the #capture.
+    let mut captures: Vec<(String, String, usize)> = Vec::new();
+    let mut loops: Vec<LoopFrame> = Vec::new();
+
+    let mut idx = 0;
+    while idx < lines.len() {
+        let line = &lines[idx];
+        let (raw_line, mut eol) = (line.text.as_str(), line.eol.as_str());
+        // Only the input file may end without a newline; an included file
+        // that does must not run into the line that follows its #include,
+        // unless asked to. Each level of nesting splices the same way.
+        if eol.is_empty() && includes.len() > 1 && !opts.no_newline_on_include {
+            eol = "\n";
+        }
+        let line_no = idx + 1;
+        idx += 1;
+        let at = |e: String| format!("{}:{}: {}", path.display(), line_no, e);
+        let locate = |e: Error| e.located(at);
+        let structure = |e: String| Error::new(ErrorKind::Structure, at(e));
+        // Already part of the directive it continues.
+        if matches!(line.directive, Some(Directive::Continuation)) {
+            continue;
+        }
+        if opts.trace
+            && let Some(directive) = &line.directive
+            && !directive.is_branch()
+        {
+            let state = if current_active { "active" } else { "inactive" };
+            eprintln!("{}:{} {} ({state})", path.display(), line_no, raw_line.trim_end());
+        }
+        if opts.strict_directives {
+            let text = if opts.allow_indented_directives {
+                raw_line.trim_start_matches([' ', '\t'])
+            } else {
+                raw_line
+            };
+            if let Some(word) = text
+                .strip_prefix(opts.directive_prefix.as_str())
+                .and_then(misspelled_directive)
+            {
+                let prefix = &opts.directive_prefix;
+                let message = format!("invalid directive: unknown directive '{prefix}{word}'");
+                return Err(Error::new(ErrorKind::Other, at(message)));
+            }
+        }
+        let consumed = match &line.directive {
+            None => false,
+            Some(Directive::Comment | Directive::Continuation) => true,
+            Some(Directive::Include { args, align }) => {
+                if current_active
+                    && let Some(include_path) = parse_include_path(args, defs)
+                {
+                    let joined = resolve_include(base_dir, &include_path, opts);
+                    check_include(&joined, includes, opts, at)?;
+                    let target = capture_target(&mut captures, out);
+                    if let Some(kind) = include_marker(opts) {
+                        push_line(target, &roundtrip_comment(kind, raw_line), eol, opts)?;
+                    }
+                    match align {
+                        Some(column) => {
+                            let mut included = String::new();
+                            let buf = &mut included;
+                            process_included(&joined, defs, buf, opts, undefined, includes)?;
+                            align_lines(target, &included, *column)?;
+                        }
+                        None => {
+                            process_included(&joined, defs, target, opts, undefined, includes)?
+                        }
+                    }
+                    if opts.roundtrippable {
+                        push_line(target, ROUNDTRIP_END_INCLUDE, eol, opts)?;
+                    }
+                    continue;
+                }
+                true
+            }
+            Some(Directive::IncludeRaw(args)) => {
+                if current_active
+                    && let Some(include_path) = parse_include_path(args, defs)
+                {
+                    let joined = resolve_include(base_dir, &include_path, opts);
+                    let Ok(content) = read_source(&joined, opts) else {
+                        missing_include(&joined, opts, at)?;
+                        continue;
+                    };
+                    opts.record_include(&joined);
+                    let target = capture_target(&mut captures, out);
+                    if let Some(kind) = include_marker(opts) {
+                        push_line(target, &roundtrip_comment(kind, raw_line), eol, opts)?;
+                    }
+                    for (text, text_eol) in split_lines(&content) {
+                        let text_eol = match text_eol {
+                            "" if !opts.no_newline_on_include => "\n",
+                            _ => text_eol,
+                        };
+                        push_line(target, text, text_eol, opts)?;
+                    }
+                    if opts.roundtrippable {
+                        push_line(target, ROUNDTRIP_END_INCLUDE, eol, opts)?;
+                    }
+                    continue;
+                }
+                true
+            }
+            Some(Directive::Use(args)) => {
+                if current_active
+                    && let Some(use_path) = parse_include_path(args, defs)
+                {
+                    let joined = resolve_include(base_dir, &use_path, opts);
+                    check_include(&joined, includes, opts, at)?;
+                    let mut discarded = String::new();
+                    // Output is discarded, so undefined variables don't matter.
+                    let undefined = UndefinedPolicy::Empty;
+                    process_included(&joined, defs, &mut discarded, opts, undefined, includes)?;
+                }
+                true
+            }
+            Some(Directive::Ifdef(name)) => {
+                let cond = defs.is_defined(&symbol_name("ifdef", name, defs).map_err(locate)?);
+                let new_active = current_active && cond;
+                stack.push(CondFrame {
+                    parent_active: current_active,
+                    line: line_no,
+                    active: cond,
+                    any_taken: cond,
+                    else_seen: false,
+                });
+                current_active = new_active;
+                true
+            }
+            Some(Directive::Ifndef(name)) => {
+                let cond = !defs.is_defined(&symbol_name("ifndef", name, defs).map_err(locate)?);
+                let new_active = current_active && cond;
+                stack.push(CondFrame {
+                    parent_active: current_active,
+                    line: line_no,
+                    active: cond,
+                    any_taken: cond,
+                    else_seen: false,
+                });
+                current_active = new_active;
+                true
+            }
+            Some(Directive::If(parsed)) => {
+                let cond =
+                    parsed.clone().and_then(|cond| eval_cond(&cond, defs, opts)).map_err(locate)?;
+                let new_active = current_active && cond;
+                stack.push(CondFrame {
+                    parent_active: current_active,
+                    line: line_no,
+                    active: cond,
+                    any_taken: cond,
+                    else_seen: false,
+                });
+                current_active = new_active;
+                true
+            }
+            Some(directive @ (Directive::Ifeq(parsed) | Directive::Ifneq(parsed))) => {
+                let (left, right) = parsed.clone().map_err(locate)?;
+                let equal = ifeq_operand(&left, defs).map_err(locate)?
+                    == ifeq_operand(&right, defs).map_err(locate)?;
+                let cond = equal == matches!(directive, Directive::Ifeq(_));
+                let new_active = current_active && cond;
+                stack.push(CondFrame {
+                    parent_active: current_active,
+                    line: line_no,
+                    active: cond,
+                    any_taken: cond,
+                    else_seen: false,
+                });
+                current_active = new_active;
+                true
+            }
+            Some(Directive::DefineInclude(parsed)) => {
+                if current_active {
+                    let (name, file) = parsed.clone().map_err(locate)?;
+                    let mut value = String::new();
+                    if let Some(include_path) = parse_include_path(&file, defs) {
+                        let joined = resolve_include(base_dir, &include_path, opts);
+                        check_include(&joined, includes, opts, at)?;
+                        process_included(&joined, defs, &mut value, opts, undefined, includes)?;
+                    }
+                    defs.define_text(&name, value);
+                }
+                true
+            }
+            Some(Directive::Define(name, value)) => {
+                if current_active {
+                    defs.set_defined(name, Some(value.clone()));
+                }
+                current_active
+            }
+            Some(Directive::Eval(parsed)) => {
+                if current_active {
+                    let (name, expr) = parsed.clone().map_err(locate)?;
+                    let value = expr.eval(defs).map_err(locate)?;
+                    if value.is_empty() {
+                        defs.set_defined(&name, None);
+                    } else {
+                        defs.set_defined(&name, Some(value));
+                    }
+                }
+                true
+            }
+            Some(Directive::Exec(parsed)) => {
+                if current_active {
+                    let (name, command) = parsed.clone().map_err(locate)?;
+                    if !opts.allow_exec {
+                        let message = "#exec is disabled; pass --allow-exec to run commands";
+                        return Err(Error::new(ErrorKind::Other, at(message.to_string())));
+                    }
+                    let value = run_command(&command).map_err(locate)?;
+                    if value.is_empty() {
+                        defs.set_defined(&name, None);
+                    } else {
+                        defs.set_defined(&name, Some(value));
+                    }
+                }
+                true
+            }
+            Some(Directive::List(parsed)) => {
+                if current_active {
+                    let (name, items) = parsed.clone().map_err(locate)?;
+                    defs.define_list(&name, items);
+                }
+                true
+            }
+            Some(Directive::Undef(name)) => {
+                if current_active {
+                    defs.set_defined(name, None);
+                }
+                current_active
+            }
+            Some(Directive::RequireDefined) => {
+                if current_active {
+                    if require_defined_start.is_some() {
+                        return Err(structure(
+                            "invalid directive structure: nested #require-defined".to_string(),
+                        ));
+                    }
+                    require_defined_start = Some((line_no, undefined));
+                    undefined = UndefinedPolicy::Error;
+                }
+                true
+            }
+            Some(Directive::EndRequireDefined) => {
+                if current_active {
+                    let Some((_, saved)) = require_defined_start.take() else {
+                        return Err(structure(
+                            "invalid directive structure: #end-require-defined without matching #require-defined"
+                                .to_string(),
+                        ));
+                    };
+                    undefined = saved;
+                }
+                true
+            }
+            Some(Directive::PragmaUndefined(parsed)) => {
+                if current_active {
+                    undefined = parsed.clone().map_err(locate)?.unwrap_or(inherited_undefined);
+                }
+                true
+            }
+            Some(Directive::PragmaOnce) => {
+                if current_active
+                    && let Some(current) = includes.last()
+                    && !opts.once.borrow_mut().insert(current.clone())
+                {
+                    // Already processed earlier in this run: skip the rest.
+                    return Ok(());
+                }
+                true
+            }
+            Some(Directive::Capture(parsed)) => {
+                if current_active {
+                    captures.push((parsed.clone().map_err(locate)?, String::new(), line_no));
+                }
+                true
+            }
+            Some(Directive::EndCapture) => {
+                if current_active {
+                    let (name, value, _) = captures.pop().ok_or_else(|| {
+                        structure(
+                            "invalid directive structure: #endcapture without matching #capture".to_string(),
+                        )
+                    })?;
+                    defs.define_text(&name, value);
+                }
+                true
+            }
+            Some(Directive::Warning(message)) => {
+                if current_active {
+                    let message = directive_message(message, defs, undefined, opts, at)?;
+                    opts.warn(&at(format!("warning: {message}")));
+                }
+                true
+            }
+            Some(Directive::Error(message)) => {
+                if current_active {
+                    let message = directive_message(message, defs, undefined, opts, at)?;
+                    // `#error` is always `Other`, whatever its message says.
+                    return Err(Error::new(ErrorKind::Other, at(format!("error: {message}"))));
+                }
+                true
+            }
+            Some(Directive::Split(parsed)) => {
+                if current_active {
+                    let spec = parsed.clone().map_err(locate)?;
+                    let text = expand_vars(&spec.text, defs, undefined, opts, at)?;
+                    // The last name takes the rest of the text; names left
+                    // without a part become undefined.
+                    let mut parts = text.splitn(spec.names.len(), spec.separator.as_str());
+                    for name in &spec.names {
+                        defs.set_defined(name, parts.next().map(str::to_string));
+                    }
+                }
+                true
+            }
+            Some(Directive::For(parsed)) => {
+                let mut frame = LoopFrame {
+                    var: String::new(),
+                    items: Vec::new(),
+                    next: 0,
+                    body_start: idx,
+                    cond_depth: stack.len(),
+                    parent_active: current_active,
+                    saved: None,
+                    broken: false,
+                };
+                if current_active {
+                    let (var, list) = parsed.clone().map_err(locate)?;
+                    let list = expand_vars(&list, defs, undefined, opts, at)?;
+                    frame.items = list.split_whitespace().map(str::to_string).collect();
+                    frame.saved = defs.is_defined(&var).then(|| defs.get_value(&var));
+                    match frame.items.first() {
+                        Some(first) => {
+                            defs.set_defined(&var, Some(first.clone()));
+                            frame.next = 1;
+                        }
+                        None => current_active = false,
+                    }
+                    frame.var = var;
+                }
+                loops.push(frame);
+                true
+            }
+            Some(Directive::EndFor) => {
+                let frame = loops.last_mut().ok_or_else(|| {
+                    structure(
+                        "invalid directive structure: #endfor without matching #for".to_string(),
+                    )
+                })?;
+                if stack.len() != frame.cond_depth {
+                    return Err(structure(
+                        "invalid directive structure: missing #endif inside #for".to_string(),
+                    ));
+                }
+                if frame.parent_active && !frame.broken && frame.next < frame.items.len() {
+                    defs.set_defined(&frame.var, Some(frame.items[frame.next].clone()));
+                    frame.next += 1;
+                    current_active = true;
+                    idx = frame.body_start;
+                } else {
+                    let frame = loops.pop().unwrap();
+                    if frame.parent_active && !frame.items.is_empty() {
+                        defs.set_defined(&frame.var, frame.saved);
+                    }
+                    current_active = frame.parent_active;
+                }
+                true
+            }
+            Some(directive @ (Directive::Break | Directive::Continue)) => {
+                if current_active {
+                    let is_break = matches!(directive, Directive::Break);
+                    let frame = loops.last_mut().ok_or_else(|| {
+                        let name = if is_break { "break" } else { "continue" };
+                        structure(format!("invalid directive structure: #{name} outside of #for"))
+                    })?;
+                    frame.broken = is_break;
+                    // Resume at the matching #endfor, dropping conditionals opened in the body.
+                    stack.truncate(frame.cond_depth);
+                    idx = find_endfor(lines, idx).ok_or_else(|| {
+                        structure("invalid directive structure: missing #endfor".to_string())
+                    })?;
+                }
+                true
+            }
+            Some(Directive::Else) => {
+                if loops.last().is_some_and(|l| l.cond_depth == stack.len()) {
+                    return Err(structure(
+                        "invalid directive structure: #else without matching #if inside #for".to_string(),
+                    ));
+                }
+                let top = stack.last_mut().ok_or_else(|| {
+                    structure(
+                        "invalid directive structure: #else without matching #if/#ifdef/#ifndef".to_string(),
+                    )
+                })?;
+                if top.else_seen {
+                    let message = "invalid directive structure: duplicate #else";
+                    return Err(structure(message.to_string()));
+                }
+                top.else_seen = true;
+                top.active = !top.any_taken;
+                top.any_taken = true;
+                current_active = top.parent_active && top.active;
+                true
+            }
+            Some(
+                directive @ (Directive::Elif(_) | Directive::Elifdef(_) | Directive::Elifndef(_)),
+            ) => {
+                let (name, cond) = match directive {
+                    Directive::Elif(parsed) => (
+                        "elif",
+                        parsed.clone().and_then(|cond| eval_cond(&cond, defs, opts)).map_err(locate)?,
+                    ),
+                    Directive::Elifdef(var) => {
+                        let var = symbol_name("elifdef", var, defs).map_err(locate)?;
+                        ("elifdef", defs.is_defined(&var))
+                    }
+                    Directive::Elifndef(var) => {
+                        let var = symbol_name("elifndef", var, defs).map_err(locate)?;
+                        ("elifndef", !defs.is_defined(&var))
+                    }
+                    _ => unreachable!(),
+                };
+                if loops.last().is_some_and(|l| l.cond_depth == stack.len()) {
+                    return Err(structure(format!(
+                        "invalid directive structure: #{name} without matching #if inside #for"
+                    )));
+                }
+                let top = stack.last_mut().ok_or_else(|| {
+                    structure(format!(
+                        "invalid directive structure: #{name} without matching #if/#ifdef/#ifndef"
+                    ))
+                })?;
+                if top.else_seen {
+                    let message = format!("invalid directive structure: #{name} after #else");
+                    return Err(structure(message));
+                }
+                top.active = !top.any_taken && cond;
+                top.any_taken |= cond;
+                current_active = top.parent_active && top.active;
+                true
+            }
+            Some(Directive::Endif) => {
+                if loops.last().is_some_and(|l| l.cond_depth == stack.len()) {
+                    return Err(structure(
+                        "invalid directive structure: #endif without matching #if inside #for".to_string(),
+                    ));
+                }
+                let top = stack.pop().ok_or_else(|| {
+                    structure(
+                        "invalid directive structure: #endif without matching #if/#ifdef/#ifndef".to_string(),
+                    )
+                })?;
+                current_active = top.parent_active;
+                true
+            }
+        };
+        if let Some(directive) = &line.directive
+            && directive.is_branch()
+        {
+            if opts.trace {
+                // Whether this branch was chosen, then whether its lines are emitted.
+                let taken = stack.last().is_some_and(|frame| frame.active);
+                let state = if current_active { "active" } else { "inactive" };
+                let text = raw_line.trim_end();
+                eprintln!("{}:{} {text} => {taken} ({state})", path.display(), line_no);
+            }
+            if opts.emit_tree {
+                opts.tree.branch(line_no, raw_line.trim_end(), current_active);
+            }
+        }
+        if consumed {
+            if opts.roundtrippable || opts.preserve_directives {
+                let target = capture_target(&mut captures, out);
+                push_line(target, &roundtrip_comment(ROUNDTRIP_DIRECTIVE, raw_line), eol, opts)?;
+            }
+            continue;
+        }
+
+        if current_active {
+            // Only the input file's own first line can be a shebang; an
+            // included file lands in the middle of the output.
+            let shebang = opts.preserve_shebang
+                && includes.len() == 1
+                && line_no == 1
+                && raw_line.starts_with("#!");
+            let replaced = if shebang {
+                raw_line.to_string()
+            } else {
+                expand_vars(raw_line, defs, undefined, opts, at)?
+            };
+            push_line(capture_target(&mut captures, out), &replaced, eol, opts)?;
+        } else if opts.roundtrippable {
+            let target = capture_target(&mut captures, out);
+            push_line(target, &roundtrip_comment(ROUNDTRIP_SKIPPED, raw_line), eol, opts)?;
+        } else if opts.preserve_directives && line.directive.is_some() {
+            // A directive in an inactive branch is still marked; its body is not.
+            let target = capture_target(&mut captures, out);
+            push_line(target, &roundtrip_comment(ROUNDTRIP_DIRECTIVE, raw_line), eol, opts)?;
+        }
+    }
+
+    // Unterminated blocks are reported at the line that opened them.
+    let unterminated = |line: usize, what: &str| {
+        let message = format!(
+            "{}:{}: invalid directive structure: missing {}",
+            path.display(),
+            line,
+            what
+        );
+        Err(Error::new(ErrorKind::Structure, message))
+    };
+    if let Some(frame) = loops.last() {
+        // The body starts right after the #for, so its index is the #for's line number.
+        return unterminated(frame.body_start, "#endfor");
+    }
+    if let Some(frame) = stack.last() {
+        return unterminated(frame.line, "#endif");
+    }
+    if let Some((_, _, line)) = captures.last() {
+        return unterminated(*line, "#endcapture");
+    }
+    if let Some((start, _)) = require_defined_start {
+        return unterminated(start, "#end-require-defined");
+    }
+
+    Ok(())
+}
+
+/// Returns the index of the `#endfor` closing the loop whose body contains
+/// `lines[from]`, skipping over nested loops.
+fn find_endfor(lines: &[Line], from: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    for (idx, line) in lines.iter().enumerate().skip(from) {
+        match line.directive {
+            Some(Directive::For(_)) => depth += 1,
+            Some(Directive::EndFor) if depth == 0 => return Some(idx),
+            Some(Directive::EndFor) => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Appends `text` and a line terminator chosen by the line-ending policy. A
+/// line that had no terminator (the end of the input) does not get one.
+fn push_line(target: &mut dyn Sink, text: &str, eol: &str, opts: &Options) -> Result<(), Error> {
+    target.emit(text)?;
+    if eol.is_empty() {
+        return Ok(());
+    }
+    target.emit(match opts.line_endings {
+        LineEndings::Crlf => "\r\n",
+        LineEndings::Preserve => eol,
+        LineEndings::Lf | LineEndings::Error => "\n",
+    })
+}
+
+/// Evaluates an `#if` or `#elif` condition, checking first for comparisons
+/// of two undefined variables under `undefined_compare_error`.
+fn eval_cond(cond: &Cond, defs: &Defs, opts: &Options) -> Result<bool, Error> {
+    if opts.undefined_compare_error
+        && let Some((left, right)) = cond.undefined_comparison(defs)
+    {
+        return Err(Error::new(
+            ErrorKind::Expr,
+            format!("invalid expression: comparing undefined variables '{left}' and '{right}'"),
+        ));
+    }
+    cond.eval(defs)
+}
+
+/// Splits `content` into lines paired with their original terminator
+/// (`"\n"`, `"\r\n"`, or `""` for a final line without one).
+fn split_lines(content: &str) -> Vec<(&str, &str)> {
+    let mut lines = Vec::new();
+    let mut rest = content;
+    while !rest.is_empty() {
+        match rest.find('\n') {
+            Some(idx) => {
+                let (line, eol) = if idx > 0 && rest.as_bytes()[idx - 1] == b'\r' {
+                    (&rest[..idx - 1], &rest[idx - 1..=idx])
+                } else {
+                    (&rest[..idx], &rest[idx..=idx])
+                };
+                lines.push((line, eol));
+                rest = &rest[idx + 1..];
+            }
+            None => {
+                lines.push((rest, ""));
+                rest = "";
+            }
+        }
+    }
+    lines
+}
+
+/// Expands `$$VAR$$` in the argument of `#ifdef` and friends, so the tested
+/// name can be computed. The result must be a valid identifier.
+fn symbol_name(directive: &str, name: &str, defs: &Defs) -> Result<String, Error> {
+    let expanded = replace_dollar_vars(name, defs, |_| Ok(()))?;
+    if !is_ident(&expanded) {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("invalid directive: #{directive} expects a variable name, got '{expanded}'"),
+        ));
+    }
+    Ok(expanded)
+}
+
+/// Runs `command` (program and arguments) and returns its stdout with
+/// surrounding whitespace trimmed. A non-zero exit is an error.
+fn run_command(command: &[String]) -> Result<String, Error> {
+    let display = command.join(" ");
+    let output = Command::new(&command[0])
+        .args(&command[1..])
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| Error::new(ErrorKind::Io, format!("cannot run '{display}': {e}")))?;
+    if !output.status.success() {
+        let message = format!("command '{display}' failed: {}", output.status);
+        return Err(Error::new(ErrorKind::Other, message));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Every directive name, for `--strict-directives`.
+const DIRECTIVE_NAMES: &[&str] = &[
+    "include", "include_raw", "use", "ifdef", "ifndef", "if", "ifeq", "ifneq", "elif", "elifdef",
+    "elifndef", "else", "endif", "define", "define-include", "eval", "exec", "list", "undef",
+    "require-defined", "end-require-defined", "pragma", "capture", "endcapture", "warning",
+    "error", "split", "for", "endfor", "break", "continue",
+];
+
+/// The word at the start of `rest`, the text after a directive prefix, if
+/// it is not a directive name but is within one or two edits of one. Words
+/// that do not start with a letter, such as in `#1`, and words far from any
+/// name, such as in `#hashtag`, are left alone.
+fn misspelled_directive(rest: &str) -> Option<&str> {
+    let len = rest
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+        .unwrap_or(rest.len());
+    let word = &rest[..len];
+    if !word.starts_with(|c: char| c.is_ascii_alphabetic()) || DIRECTIVE_NAMES.contains(&word) {
+        return None;
+    }
+    // Short names allow a single edit, so `#it` is flagged but `#tag` is not.
+    let close = |name: &&str| edit_distance(word, name) <= if name.len() <= 4 { 1 } else { 2 };
+    DIRECTIVE_NAMES.iter().any(close).then_some(word)
+}
+
+/// Levenshtein distance between `a` and `b`, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = prev[j] + usize::from(ca != *cb);
+            row.push(substitute.min(prev[j + 1] + 1).min(row[j] + 1));
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+fn directive_args<'a>(line: &'a str, kw: &str) -> Option<&'a str> {
+    let rest = line.strip_prefix(kw)?;
+    if rest.is_empty() || rest.chars().next().is_some_and(|c| c.is_whitespace()) {
+        Some(rest.trim_start())
+    } else {
+        None
+    }
+}
+
+/// Like [`directive_args`], but also accepts the `(` of `#ifeq(A, B)` right
+/// after the keyword, so it is not read as `#if eq(A, B)`.
+fn ifeq_directive_args<'a>(line: &'a str, kw: &str) -> Option<&'a str> {
+    match line.strip_prefix(kw) {
+        Some(rest) if rest.starts_with('(') => Some(rest),
+        _ => directive_args(line, kw),
+    }
+}
+
+/// Splits the `(A, B)` of `#ifeq` and `#ifneq` into its two operands. A
+/// comma inside double quotes does not separate them.
+fn parse_ifeq_args(directive: &str, args: &str) -> Result<(String, String), Error> {
+    let malformed = || {
+        let message = format!("#{directive} expects '(A, B)', got '{args}'");
+        Error::new(ErrorKind::Structure, format!("invalid directive structure: {message}"))
+    };
+    let inner = args
+        .strip_prefix('(')
+        .and_then(|inner| inner.strip_suffix(')'))
+        .ok_or_else(malformed)?;
+    if inner.matches('"').count() % 2 != 0 {
+        return Err(malformed());
+    }
+    let mut quoted = false;
+    let comma = inner
+        .char_indices()
+        .find(|&(_, c)| {
+            if c == '"' {
+                quoted = !quoted;
+            }
+            c == ',' && !quoted
+        })
+        .ok_or_else(malformed)?
+        .0;
+    Ok((inner[..comma].trim().to_string(), inner[comma + 1..].trim().to_string()))
+}
+
+/// The value of one `#ifeq` operand: its text, unquoted, with `$$VAR$$`
+/// expanded. A bare word is literal text, not a variable.
+fn ifeq_operand(operand: &str, defs: &Defs) -> Result<String, Error> {
+    replace_dollar_vars(unquote(operand), defs, |_| Ok(()))
+}
+
+fn parse_single_ident(args: &str) -> Option<String> {
+    let name = args.trim();
+    if is_ident(name) {
+        Some(name.to_string())
+    } else {
+        None
+    }
+}
+
+/// Parses `#define` arguments: `KEY` (value `TRUE`), `KEY VALUE`, or
+/// `KEY=VALUE`. `KEY=` and `KEY ""` define `KEY` with an empty value.
+fn parse_define_args(args: &str) -> Option<(String, String)> {
+    let trimmed = args.trim_start();
+    let name_len = trimmed
+        .find(|c: char| c.is_whitespace() || c == '=')
+        .unwrap_or(trimmed.len());
+    let (name, rest) = trimmed.split_at(name_len);
+    if !is_ident(name) {
+        return None;
+    }
+
+    let value = match rest.strip_prefix('=') {
+        Some(value) => value,
+        None if rest.trim().is_empty() => "TRUE",
+        None => rest.trim_start(),
+    };
+    let value = if value == "\"\"" { "" } else { value };
+    Some((name.to_string(), value.to_string()))
+}
+
+/// The path of an include directive, with `##VAR##` replaced. Unlike
+/// messages (see [`directive_message`]), paths never expand `$$VAR$$`.
+fn parse_include_path(args: &str, defs: &Defs) -> Option<PathBuf> {
+    let replaced = replace_hash_vars(include_arg(args)?, defs);
+    if replaced.is_empty() {
+        None
+    } else {
+        Some(PathBuf::from(replaced))
+    }
+}
+
+/// The path named by include arguments, before `##VAR##` replacement:
+/// everything between the opening double quote and the last one, or else the
+/// first whitespace-delimited word.
+fn include_arg(args: &str) -> Option<&str> {
+    let after = args.trim();
+    match after.strip_prefix('"').and_then(|rest| rest.rsplit_once('"')) {
+        Some((quoted, _)) => Some(quoted),
+        None => after.split_whitespace().next(),
+    }
+}
+
+/// Resolves an include path against the including file's directory, falling
+/// back to the first `-I` directory that has it. A path found nowhere is
+/// returned relative to `base_dir`.
+fn resolve_include(base_dir: &Path, path: &Path, opts: &Options) -> PathBuf {
+    let joined = base_dir.join(path);
+    if joined.exists() {
+        return joined;
+    }
+    opts.include_dirs
+        .iter()
+        .map(|dir| dir.join(path))
+        .find(|candidate| candidate.exists())
+        .unwrap_or(joined)
+}
+
+/// Splits a trailing `align N` off `#include` arguments.
+fn split_include_align(args: &str) -> (&str, Option<usize>) {
+    if let Some((path, column)) = args.trim_end().rsplit_once(" align ")
+        && let Ok(column) = column.trim().parse()
+    {
+        return (path, Some(column));
+    }
+    (args, None)
+}
+
+/// Appends `text` to `target` with every non-empty line indented by `column`
+/// spaces, so included content starts at that (0-based) column.
+fn align_lines(target: &mut dyn Sink, text: &str, column: usize) -> Result<(), Error> {
+    for (line, eol) in split_lines(text) {
+        if !line.is_empty() {
+            target.emit(&" ".repeat(column))?;
+        }
+        target.emit(line)?;
+        target.emit(eol)?;
+    }
+    Ok(())
+}
+
+// The scanners below walk bytes but only ever split the input at an ASCII
+// `#`, `$` or `\`, so text between markers is copied as whole UTF-8 slices.
+
+/// Replaces each `##VAR##` with the value of `VAR`. `\##` and `\#\#` are
+/// copied as a literal `##`.
+fn replace_hash_vars(input: &str, defs: &Defs) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut i = 0;
+    let mut copied = 0;
+    let bytes = input.as_bytes();
+    while i + 1 < bytes.len() {
+        let escape = [b"\\##".as_slice(), b"\\#\\#"].into_iter().find(|e| bytes[i..].starts_with(e));
+        if let Some(escape) = escape {
+            out.push_str(&input[copied..i]);
+            out.push_str("##");
+            i += escape.len();
+            copied = i;
+            continue;
+        }
+        if bytes[i] == b'#'
+            && bytes[i + 1] == b'#'
+            && let Some(end) = find_double_hash_end(bytes, i + 2)
+        {
+            out.push_str(&input[copied..i]);
+            let (name, fallback) = split_fallback(&input[i + 2..end]);
+            if is_ident(name)
+                && let Some(value) = defs.lookup(name).or(fallback)
+            {
+                out.push_str(value);
+            }
+            i = end + 2;
+            copied = i;
+            continue;
+        }
+        i += 1;
+    }
+    out.push_str(&input[copied..]);
+    out
+}
+
+/// Strips one pair of double quotes wrapping `text`, if present.
+fn unquote(text: &str) -> &str {
+    text.strip_prefix('"')
+        .and_then(|t| t.strip_suffix('"'))
+        .unwrap_or(text)
+}
+
+/// Splits `NAME:-fallback` into the name and the text used when `NAME` is
+/// undefined.
+fn split_fallback(inner: &str) -> (&str, Option<&str>) {
+    match inner.split_once(":-") {
+        Some((name, fallback)) => (name, Some(fallback)),
+        None => (inner, None),
+    }
+}
+
+fn find_double_hash_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut j = start;
+    while j + 1 < bytes.len() {
+        if bytes[j] == b'#' && bytes[j + 1] == b'#' {
+            return Some(j);
+        }
+        j += 1;
+    }
+    None
+}
+
+/// The text of a directive message such as `#warning "..."`: one pair of
+/// wrapping quotes is dropped and `$$VAR$$` is expanded like in body lines,
+/// following `--warn-undefined`, `--error-undefined` and
+/// `--expand-recursive`. Directives that report text should all go through
+/// here.
+///
+/// Include paths deliberately use `##VAR##` instead (see
+/// [`parse_include_path`]), so a `$$` in a file name is kept and undefined
+/// names in a path never warn.
+fn directive_message(
+    message: &str,
+    defs: &Defs,
+    policy: UndefinedPolicy,
+    opts: &Options,
+    at: impl Fn(String) -> String,
+) -> Result<String, Error> {
+    expand_vars(unquote(message), defs, policy, opts, at)
+}
+
+/// Expands `$$VAR$$` in `input`, handling undefined names as `policy` says,
+/// and repeats until nothing changes if `opts.expand_recursive` is set.
+/// `at` adds the location to warnings and errors.
+///
+/// When repeating, escaped `\$$` is kept until the passes converge and only
+/// then turned into `$$`, so a later pass cannot expand it.
+fn expand_vars(
+    input: &str,
+    defs: &Defs,
+    policy: UndefinedPolicy,
+    opts: &Options,
+    at: impl Fn(String) -> String,
+) -> Result<String, Error> {
+    let expand_once = |text: &str, unescape: bool| {
+        substitute_dollar_vars(text, defs, unescape, |name| match policy {
+            UndefinedPolicy::Empty => Ok(()),
+            UndefinedPolicy::Warn => {
+                opts.warn(&at(format!("warning: undefined variable '{name}'")));
+                Ok(())
+            }
+            UndefinedPolicy::Error => {
+                Err(Error::new(ErrorKind::Other, at(format!("undefined variable '{name}'"))))
+            }
+        })
+    };
+    if !opts.expand_recursive {
+        return expand_once(input, true);
+    }
+    let mut expanded = expand_once(input, false)?;
+    for _ in 1..MAX_EXPANSION_PASSES {
+        let next = expand_once(&expanded, false)?;
+        if next == expanded {
+            return Ok(expanded.replace("\\$$", "$$"));
+        }
+        expanded = next;
+    }
+    let message = at("variable expansion did not converge".to_string());
+    Err(Error::new(ErrorKind::Expr, message))
+}
+
+/// Replaces each `$$VAR$$` and `${VAR}` with the value of `VAR`, calling
+/// `on_undefined` for each undefined `VAR` (which still expands to an empty
+/// string). `$$VAR:-fallback$$` expands to `fallback` instead when `VAR` is
+/// undefined. A `$` starting neither form is copied as is, and `\$$` is
+/// copied as a literal `$$`.
+fn replace_dollar_vars(
+    input: &str,
+    defs: &Defs,
+    on_undefined: impl FnMut(&str) -> Result<(), Error>,
+) -> Result<String, Error> {
+    substitute_dollar_vars(input, defs, true, on_undefined)
+}
+
+/// Like [`replace_dollar_vars`], but copies `\$$` through unchanged unless
+/// `unescape` is set.
+fn substitute_dollar_vars(
+    input: &str,
+    defs: &Defs,
+    unescape: bool,
+    mut on_undefined: impl FnMut(&str) -> Result<(), Error>,
+) -> Result<String, Error> {
+    let mut out = String::with_capacity(input.len());
+    let mut i = 0;
+    let mut copied = 0;
+    let bytes = input.as_bytes();
+    while i + 1 < bytes.len() {
+        if bytes[i..].starts_with(b"\\$$") {
+            if unescape {
+                out.push_str(&input[copied..i]);
+                out.push_str("$$");
+                copied = i + 3;
+            }
+            i += 3;
+            continue;
+        }
+        if bytes[i] == b'$'
+            && bytes[i + 1] == b'$'
+            && let Some(end) = find_double_dollar_end(bytes, i + 2)
+        {
+            out.push_str(&input[copied..i]);
+            let inner = &input[i + 2..end];
+            let (spec, joiner) = match inner.split_once('|') {
+                Some((spec, joiner)) => (spec, Some(joiner)),
+                None => (inner, None),
+            };
+            let (name, fallback) = split_fallback(spec);
+            if is_ident(name) {
+                match (joiner, defs.list(name)) {
+                    (Some(joiner), Some(items)) => out.push_str(&items.join(joiner)),
+                    _ => match defs.lookup(name).or(fallback) {
+                        Some(value) => out.push_str(value),
+                        None => on_undefined(name)?,
+                    },
+                }
+            }
+            i = end + 2;
+            copied = i;
+            continue;
+        }
+        if bytes[i] == b'$'
+            && bytes[i + 1] == b'{'
+            && let Some(len) = input[i + 2..].find('}')
+            && is_ident(&input[i + 2..i + 2 + len])
+        {
+            out.push_str(&input[copied..i]);
+            let name = &input[i + 2..i + 2 + len];
+            match defs.lookup(name) {
+                Some(value) => out.push_str(value),
+                None => on_undefined(name)?,
+            }
+            i += len + 3;
+            copied = i;
+            continue;
+        }
+        i += 1;
+    }
+    out.push_str(&input[copied..]);
+    Ok(out)
+}
+
+fn find_double_dollar_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut j = start;
+    while j + 1 < bytes.len() {
+        if bytes[j] == b'$' && bytes[j + 1] == b'$' {
+            return Some(j);
+        }
+        j += 1;
+    }
+    None
+}
+
+pub fn is_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    for c in chars {
+        if !(c.is_ascii_alphanumeric() || c == '_') {
+            return false;
+        }
+    }
+    true
+}
+
+/// `--roundtrippable` comment markers. A consumed directive becomes
+/// `<!-- textpp: #directive -->`, a line dropped by an inactive branch becomes
+/// `<!-- textpp-skip: line -->`, and included content is wrapped between
+/// `<!-- textpp-include: #include ... -->` and `<!-- textpp-end-include -->`.
+const ROUNDTRIP_DIRECTIVE: &str = "textpp";
+const ROUNDTRIP_SKIPPED: &str = "textpp-skip";
+const ROUNDTRIP_INCLUDE: &str = "textpp-include";
+const ROUNDTRIP_END_INCLUDE: &str = "<!-- textpp-end-include -->";
+
+/// The marker kind written before included content: the `--roundtrippable`
+/// include marker, or a plain directive marker for `--preserve-directives`.
+fn include_marker(opts: &Options) -> Option<&'static str> {
+    if opts.roundtrippable {
+        Some(ROUNDTRIP_INCLUDE)
+    } else if opts.preserve_directives {
+        Some(ROUNDTRIP_DIRECTIVE)
+    } else {
+        None
+    }
+}
+
+fn roundtrip_comment(kind: &str, line: &str) -> String {
+    // `--` may not appear inside an HTML comment, so escape it (and `%`).
+    let escaped = line.replace('%', "%25").replace("--", "%2D%2D");
+    format!("<!-- {kind}: {escaped} -->")
+}
+
+fn parse_roundtrip_comment(line: &str) -> Option<(&str, String)> {
+    let body = line.strip_prefix("<!-- ")?.strip_suffix(" -->")?;
+    let (kind, escaped) = body.split_once(": ")?;
+    if ![ROUNDTRIP_DIRECTIVE, ROUNDTRIP_SKIPPED, ROUNDTRIP_INCLUDE].contains(&kind) {
+        return None;
+    }
+    let mut decoded = String::with_capacity(escaped.len());
+    let mut rest = escaped;
+    while let Some(idx) = rest.find('%') {
+        decoded.push_str(&rest[..idx]);
+        let code = rest.get(idx..idx + 3);
+        match code {
+            Some("%25") => decoded.push('%'),
+            Some("%2D") => decoded.push('-'),
+            _ => return None,
+        }
+        rest = &rest[idx + 3..];
+    }
+    decoded.push_str(rest);
+    Some((kind, decoded))
+}
+
+/// Reverses `--roundtrippable`: restores directive and skipped lines from
+/// their comments and drops included content so the `#include` expands again.
+pub fn rehydrate_content(content: &str) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut include_depth = 0usize;
+    for (line, eol) in split_lines(content) {
+        if line == ROUNDTRIP_END_INCLUDE && include_depth > 0 {
+            include_depth -= 1;
+            continue;
+        }
+        let restored = match parse_roundtrip_comment(line) {
+            Some((ROUNDTRIP_INCLUDE, directive)) => {
+                include_depth += 1;
+                if include_depth > 1 {
+                    continue;
+                }
+                directive
+            }
+            _ if include_depth > 0 => continue,
+            Some((_, original)) => original,
+            None => line.to_string(),
+        };
+        out.push_str(&restored);
+        out.push_str(eol);
+    }
+    out
+}
+
@@ -1,559 +1,1087 @@
-use std::collections::HashMap;
+mod regex;
+mod sha256;
+
 use std::env;
 use std::fs;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 
+use regex::Regex;
+use sha256::sha256_hex;
+use textpp::{
+    apply_define, apply_define_line, apply_defines_file_line, directive_ast, is_ident,
+    process_content, process_content_to, process_file, process_file_to, rehydrate_content,
+    DefineArg, Defs, Error, ErrorKind, InputEncoding, LineEndings, Options, UndefinedPolicy,
+};
+
+/// How `--in-place-suffix` / `--in-place` derive an output path from an input path.
 #[derive(Debug, Clone)]
-struct Defs {
-    values: HashMap<String, String>,
-    defined: HashMap<String, bool>,
+enum DerivedOutput {
+    AppendSuffix(String),
+    StripTemplateSuffix,
 }
 
-impl Defs {
-    fn new() -> Self {
-        Self {
-            values: HashMap::new(),
-            defined: HashMap::new(),
-        }
-    }
+/// A `-D` flag, `--defines-file` or `--define-file`, kept in command-line order.
+enum CliDefine {
+    Spec(String),
+    File(String),
+    /// `--define-file KEY=PATH`: `KEY` is set to the contents of `PATH`.
+    Contents(String),
+}
 
-    fn set_defined(&mut self, key: &str, value: Option<String>) {
-        match value {
-            Some(v) => {
-                self.values.insert(key.to_string(), v);
-                self.defined.insert(key.to_string(), true);
-            }
-            None => {
-                self.values.remove(key);
-                self.defined.insert(key.to_string(), false);
-            }
-        }
-    }
+/// The default name used for input read from stdin, in error messages.
+const STDIN_NAME: &str = "<stdin>";
 
-    fn is_defined(&self, key: &str) -> bool {
-        self.defined.get(key).copied().unwrap_or(false)
-    }
+/// Printed by `--help`, and when no input is given.
+const USAGE: &str = "usage: textpp [-h|--help] [-V|--version] [@<response-file>]... [-DKEY[=VALUE]] [--decode-escapes] [-U|--undef <key>]... [--import-env-regex <regex> [--strip-env-prefix <prefix>]] [--import-env <prefix>]... [--defines-stdin] [--stdin-name <name>] [--defines-file <file>]... [--define-file <key>=<file>]... [--line-endings=lf|crlf|preserve|error | --preserve-eol] [--input-encoding <encoding>] [--line-prefix <text>] [--line-suffix <text>] [--preserve-shebang] [--roundtrippable | --rehydrate] [--preserve-directives] [--check] [--emit-ast] [-q] [--fail-on-warning] [--error-if-empty] [--print-hash] [--dump-defines] [--list-includes | -M] [-o|--output <output-file> [--diff] | [--in-place-suffix <suffix> | --in-place] [--out-dir <dir>] [--continue-on-error]] [--input-glob <pattern>]... [-I <dir>]... [--include-base <dir>] [--warn-missing-include-dirs] [--max-include-depth <n>] [--max-output-bytes <n>] [--strict-includes [--keep-going]] [--warn-empty-includes] [--no-newline-on-include] [--emit-tree-json <file>] [--expand-recursive] [--allow-exec] [--trace] [--allow-indented-directives] [--directive-prefix <prefix>] [--strict-directives] [--undefined-compare-error] [--warn-undefined | --error-undefined] <input-file>...";
 
-    fn get_value(&self, key: &str) -> String {
-        if self.is_defined(key) {
-            self.values.get(key).cloned().unwrap_or_else(|| "TRUE".to_string())
-        } else {
-            String::new()
+/// Suffixes recognized by `--in-place` as marking a template file.
+const TEMPLATE_SUFFIXES: &[&str] = &[".in", ".tpl", ".tmpl", ".template"];
+
+impl DerivedOutput {
+    fn output_path(&self, input: &str) -> Result<String, String> {
+        match self {
+            Self::AppendSuffix(suffix) => Ok(format!("{input}{suffix}")),
+            Self::StripTemplateSuffix => TEMPLATE_SUFFIXES
+                .iter()
+                .find_map(|suffix| input.strip_suffix(suffix))
+                .filter(|stripped| !stripped.is_empty())
+                .map(str::to_string)
+                .ok_or_else(|| {
+                    format!(
+                        "{input}: --in-place needs a template suffix ({})",
+                        TEMPLATE_SUFFIXES.join(", ")
+                    )
+                }),
         }
     }
 }
 
-#[derive(Debug)]
-struct CondFrame {
-    parent_active: bool,
-    active: bool,
-    else_seen: bool,
-}
-
 fn main() {
     let mut defs = Defs::new();
-    let mut input: Option<String> = None;
+    let mut inputs: Vec<String> = Vec::new();
+    let mut output: Option<String> = None;
+    let mut derived: Option<DerivedOutput> = None;
+    let mut diff = false;
+    let mut rehydrate = false;
+    let mut check = false;
+    let mut emit_ast = false;
+    let mut fail_on_warning = false;
+    let mut error_if_empty = false;
+    let mut print_hash = false;
+    let mut dump_defines = false;
+    let mut defines_stdin = false;
+    let mut decode_escapes = false;
+    let mut stdin_name = STDIN_NAME.to_string();
+    let mut env_regex: Option<String> = None;
+    let mut env_prefixes: Vec<String> = Vec::new();
+    let mut env_strip_prefix: Option<String> = None;
+    let mut cli_defines: Vec<CliDefine> = Vec::new();
+    let mut undefs: Vec<String> = Vec::new();
+    let mut input_globs: Vec<String> = Vec::new();
+    let mut out_dir: Option<String> = None;
+    let mut continue_on_error = false;
+    let mut include_dirs: Vec<String> = Vec::new();
+    let mut warn_missing_include_dirs = false;
+    let mut tree_json: Option<String> = None;
+    let mut opts = Options::default();
 
-    for arg in env::args().skip(1) {
-        if let Some(rest) = arg.strip_prefix("-D") {
-            if rest.is_empty() {
-                continue;
+    let args = match expand_response_files(env::args().skip(1), &mut Vec::new()) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if let Some(define) = DefineArg::parse(&arg, &mut args) {
+            match define {
+                DefineArg::Define(spec) => cli_defines.push(CliDefine::Spec(spec)),
+                DefineArg::Undef(key) => undefs.push(key),
+                DefineArg::Empty => {}
             }
-            if let Some((k, v)) = rest.split_once('=') {
-                if v.is_empty() {
-                    defs.set_defined(k, None);
-                } else {
-                    defs.set_defined(k, Some(v.to_string()));
+        } else if arg == "--defines-file" {
+            cli_defines.extend(args.next().map(CliDefine::File));
+        } else if arg == "--define-file" {
+            cli_defines.extend(args.next().map(CliDefine::Contents));
+        } else if arg == "-I" {
+            include_dirs.extend(args.next());
+        } else if let Some(dir) = arg.strip_prefix("-I") {
+            include_dirs.push(dir.to_string());
+        } else if arg == "--include-base" {
+            opts.include_base = args.next().map(PathBuf::from);
+        } else if arg == "--warn-missing-include-dirs" {
+            warn_missing_include_dirs = true;
+        } else if arg == "--decode-escapes" {
+            decode_escapes = true;
+        } else if arg == "--defines-stdin" {
+            defines_stdin = true;
+        } else if arg == "--stdin-name" {
+            stdin_name = args.next().unwrap_or_else(|| STDIN_NAME.to_string());
+        } else if arg == "--import-env-regex" {
+            env_regex = args.next();
+        } else if arg == "--import-env" {
+            env_prefixes.extend(args.next());
+        } else if arg == "--strip-env-prefix" {
+            env_strip_prefix = args.next();
+        } else if arg == "-o" || arg == "--output" {
+            output = args.next();
+        } else if arg == "-h" || arg == "--help" {
+            println!("{USAGE}");
+            std::process::exit(0);
+        } else if arg == "-V" || arg == "--version" {
+            println!("textpp {}", env!("CARGO_PKG_VERSION"));
+            std::process::exit(0);
+        } else if arg == "--self-test" {
+            std::process::exit(if self_test() { 0 } else { 1 });
+        } else if arg == "--diff" {
+            diff = true;
+        } else if arg == "-q" || arg == "--quiet" {
+            opts.quiet = true;
+        } else if arg == "--fail-on-warning" {
+            fail_on_warning = true;
+        } else if arg == "--error-if-empty" {
+            error_if_empty = true;
+        } else if arg == "--print-hash" {
+            print_hash = true;
+        } else if arg == "--list-includes" || arg == "-M" {
+            opts.list_includes = true;
+        } else if arg == "--dump-defines" {
+            dump_defines = true;
+        } else if arg == "--no-newline-on-include" {
+            opts.no_newline_on_include = true;
+        } else if arg == "--roundtrippable" {
+            opts.roundtrippable = true;
+        } else if arg == "--preserve-directives" {
+            opts.preserve_directives = true;
+        } else if arg == "--rehydrate" {
+            rehydrate = true;
+        } else if arg == "--check" {
+            check = true;
+        } else if arg == "--emit-ast" {
+            emit_ast = true;
+        } else if arg == "--preserve-shebang" {
+            opts.preserve_shebang = true;
+        } else if arg == "--in-place-suffix" {
+            derived = args.next().map(DerivedOutput::AppendSuffix);
+        } else if arg == "--in-place" {
+            derived = Some(DerivedOutput::StripTemplateSuffix);
+        } else if arg == "--input-glob" {
+            input_globs.extend(args.next());
+        } else if arg == "--out-dir" {
+            out_dir = args.next();
+        } else if arg == "--emit-tree-json" {
+            tree_json = args.next();
+            opts.emit_tree = tree_json.is_some();
+        } else if arg == "--max-include-depth" {
+            let value = args.next().unwrap_or_default();
+            opts.max_include_depth = match value.parse() {
+                Ok(depth) => depth,
+                Err(_) => {
+                    eprintln!("invalid --max-include-depth value '{value}': expected a non-negative integer");
+                    std::process::exit(2);
                 }
-            } else {
-                defs.set_defined(rest, Some("TRUE".to_string()));
+            };
+        } else if arg == "--max-output-bytes" {
+            let value = args.next().unwrap_or_default();
+            opts.max_output_bytes = match value.parse() {
+                Ok(n) => Some(n),
+                Err(_) => {
+                    eprintln!("invalid --max-output-bytes value '{value}': expected a non-negative integer");
+                    std::process::exit(2);
+                }
+            };
+        } else if arg == "--line-prefix" {
+            opts.line_prefix = args.next().unwrap_or_default();
+        } else if arg == "--line-suffix" {
+            opts.line_suffix = args.next().unwrap_or_default();
+        } else if arg == "--input-encoding" {
+            let value = args.next().unwrap_or_default();
+            opts.input_encoding = match InputEncoding::parse(&value) {
+                Some(encoding) => encoding,
+                None => {
+                    eprintln!("unknown --input-encoding '{value}': expected utf-8 or latin1");
+                    std::process::exit(2);
+                }
+            };
+        } else if arg == "--strict-includes" {
+            opts.strict_includes = true;
+        } else if arg == "--keep-going" {
+            opts.keep_going = true;
+        } else if arg == "--warn-undefined" {
+            opts.undefined = UndefinedPolicy::Warn;
+        } else if arg == "--error-undefined" {
+            opts.undefined = UndefinedPolicy::Error;
+        } else if arg == "--undefined-compare-error" {
+            opts.undefined_compare_error = true;
+        } else if arg == "--expand-recursive" {
+            opts.expand_recursive = true;
+        } else if arg == "--allow-exec" {
+            opts.allow_exec = true;
+        } else if arg == "--trace" {
+            opts.trace = true;
+        } else if arg == "--allow-indented-directives" {
+            opts.allow_indented_directives = true;
+        } else if arg == "--directive-prefix" {
+            opts.directive_prefix = args.next().unwrap_or_default();
+            if opts.directive_prefix.is_empty() {
+                eprintln!("--directive-prefix must not be empty");
+                std::process::exit(2);
             }
-        } else if input.is_none() {
-            input = Some(arg);
+        } else if arg == "--strict-directives" {
+            opts.strict_directives = true;
+        } else if arg == "--warn-empty-includes" {
+            opts.warn_empty_includes = true;
+        } else if arg == "--continue-on-error" {
+            continue_on_error = true;
+        } else if arg == "--preserve-eol" {
+            opts.line_endings = LineEndings::Preserve;
+        } else if let Some(value) = arg.strip_prefix("--line-endings=") {
+            opts.line_endings = match LineEndings::parse(value) {
+                Some(v) => v,
+                None => {
+                    eprintln!("invalid --line-endings value '{value}': expected lf, crlf, preserve or error");
+                    std::process::exit(2);
+                }
+            };
+        } else {
+            inputs.push(arg);
         }
     }
 
-    let input = match input {
-        Some(v) => v,
-        None => {
-            eprintln!("usage: textpp [-DKEY[=VALUE]] <input-file>");
-            std::process::exit(2);
-        }
-    };
-
-    let input_path = PathBuf::from(&input);
-    let mut out = String::new();
-    match process_file(&input_path, &mut defs, &mut out) {
-        Ok(()) => {
-            print!("{out}");
-        }
-        Err(e) => {
-            eprintln!("{e}");
+    for pattern in &input_globs {
+        let matches = glob(pattern);
+        if matches.is_empty() {
+            eprintln!("no files match --input-glob '{pattern}'");
             std::process::exit(1);
         }
+        inputs.extend(matches);
     }
-}
 
-fn process_file(path: &Path, defs: &mut Defs, out: &mut String) -> Result<(), String> {
-    let content = match fs::read_to_string(path) {
-        Ok(c) => c,
-        Err(_) => return Ok(()),
-    };
-    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
-    let mut stack: Vec<CondFrame> = Vec::new();
-    let mut current_active = true;
-
-    for raw_line in content.lines() {
-        if let Some(rest) = raw_line.strip_prefix('#') {
-            let trimmed = rest.trim_start();
-            if trimmed.starts_with("include") {
-                if current_active {
-                    if let Some(include_path) = parse_include_path(trimmed, defs) {
-                        let joined = base_dir.join(include_path);
-                        let _ = process_file(&joined, defs, out);
-                    }
-                }
-                continue;
-            }
-            if trimmed.starts_with("ifdef") {
-                let name = trimmed["ifdef".len()..].trim();
-                let cond = defs.is_defined(name);
-                let new_active = current_active && cond;
-                stack.push(CondFrame {
-                    parent_active: current_active,
-                    active: cond,
-                    else_seen: false,
-                });
-                current_active = new_active;
-                continue;
+    if inputs.is_empty() {
+        eprintln!("{USAGE}");
+        std::process::exit(2);
+    }
+    if diff && output.is_none() {
+        eprintln!("--diff requires -o <output-file>");
+        std::process::exit(2);
+    }
+    if opts.roundtrippable && !(opts.line_prefix.is_empty() && opts.line_suffix.is_empty()) {
+        eprintln!("--line-prefix and --line-suffix cannot be combined with --roundtrippable");
+        std::process::exit(2);
+    }
+    if opts.roundtrippable && opts.no_newline_on_include {
+        eprintln!("--no-newline-on-include cannot be combined with --roundtrippable");
+        std::process::exit(2);
+    }
+    let writes_output = output.is_some() || derived.is_some() || out_dir.is_some();
+    if check && (writes_output || rehydrate || print_hash || opts.list_includes) {
+        eprintln!("--check cannot be combined with options that produce output");
+        std::process::exit(2);
+    }
+    if error_if_empty && (check || emit_ast) {
+        eprintln!("--error-if-empty cannot be combined with --check or --emit-ast");
+        std::process::exit(2);
+    }
+    let other_modes = check || rehydrate || print_hash || opts.list_includes || tree_json.is_some();
+    if emit_ast && (writes_output || other_modes) {
+        eprintln!("--emit-ast cannot be combined with options that process or write output");
+        std::process::exit(2);
+    }
+    if opts.list_includes && (rehydrate || tree_json.as_deref() == Some("-")) {
+        eprintln!("--list-includes cannot be combined with --rehydrate or --emit-tree-json -");
+        std::process::exit(2);
+    }
+
+    if let Some(pattern) = &env_regex {
+        let regex = match Regex::new(pattern) {
+            Ok(regex) => regex,
+            Err(e) => {
+                eprintln!("invalid --import-env-regex '{pattern}': {e}");
+                std::process::exit(2);
             }
-            if trimmed.starts_with("ifndef") {
-                let name = trimmed["ifndef".len()..].trim();
-                let cond = !defs.is_defined(name);
-                let new_active = current_active && cond;
-                stack.push(CondFrame {
-                    parent_active: current_active,
-                    active: cond,
-                    else_seen: false,
-                });
-                current_active = new_active;
-                continue;
+        };
+        let strip_prefix = env_strip_prefix.as_deref().unwrap_or("");
+        import_env(&mut defs, |name| regex.is_match(name), strip_prefix);
+    }
+    for prefix in &env_prefixes {
+        import_env(&mut defs, |name| name.starts_with(prefix.as_str()), prefix);
+    }
+    // `TEXTPP_DEFINES=KEY1=VAL1;KEY2=VAL2` applies before the defines given on
+    // the command line. A bad entry is skipped rather than fatal.
+    if let Ok(text) = env::var("TEXTPP_DEFINES") {
+        for entry in text.split(';') {
+            if entry.trim().is_empty() {
+                opts.warn("warning: ignoring empty TEXTPP_DEFINES entry");
+            } else if let Err(e) = apply_define_line(&mut defs, entry) {
+                opts.warn(&format!("warning: ignoring TEXTPP_DEFINES entry: {e}"));
             }
-            if trimmed.starts_with("if") {
-                let expr = trimmed["if".len()..].trim();
-                let cond = eval_expr(expr, defs)?;
-                let new_active = current_active && cond;
-                stack.push(CondFrame {
-                    parent_active: current_active,
-                    active: cond,
-                    else_seen: false,
-                });
-                current_active = new_active;
-                continue;
+        }
+    }
+    if defines_stdin && inputs.iter().any(|input| input == "-") {
+        eprintln!("--defines-stdin cannot be combined with reading the input from stdin ('-')");
+        std::process::exit(2);
+    }
+    if defines_stdin {
+        let mut text = String::new();
+        if let Err(e) = io::stdin().read_to_string(&mut text) {
+            eprintln!("cannot read defines from stdin: {e}");
+            std::process::exit(1);
+        }
+        for (line_no, line) in text.lines().enumerate() {
+            if let Err(e) = apply_defines_file_line(&mut defs, line) {
+                eprintln!("{stdin_name}:{}: {e}", line_no + 1);
+                std::process::exit(2);
             }
-            if let Some(args) = directive_args(trimmed, "define") {
-                if current_active {
-                    if let Some((name, value)) = parse_define_args(args) {
-                        defs.set_defined(&name, Some(value));
-                        continue;
+        }
+    }
+    // -D flags and --defines-file are applied last, in command-line order, so
+    // they override defines read from elsewhere and later ones win.
+    for define in &cli_defines {
+        match define {
+            CliDefine::Spec(spec) => match spec.split_once('=') {
+                Some((key, value)) if decode_escapes => {
+                    apply_define(&mut defs, &format!("{key}={}", unescape(value)));
+                }
+                _ => apply_define(&mut defs, spec),
+            },
+            CliDefine::File(path) => {
+                let text = match fs::read_to_string(path) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        eprintln!("cannot read defines file {path}: {e}");
+                        std::process::exit(1);
+                    }
+                };
+                for (line_no, line) in text.lines().enumerate() {
+                    if let Err(e) = apply_defines_file_line(&mut defs, line) {
+                        eprintln!("{path}:{}: {e}", line_no + 1);
+                        std::process::exit(2);
                     }
                 }
             }
-            if let Some(args) = directive_args(trimmed, "undef") {
-                if current_active {
-                    if let Some(name) = parse_single_ident(args) {
-                        defs.set_defined(&name, None);
-                        continue;
+            CliDefine::Contents(spec) => {
+                let Some((key, path)) = spec.split_once('=').filter(|(key, _)| is_ident(key)) else {
+                    eprintln!("invalid --define-file value '{spec}': expected KEY=PATH");
+                    std::process::exit(2);
+                };
+                let text = fs::read(path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|bytes| opts.input_encoding.decode(bytes));
+                match text {
+                    Ok(text) => defs.define_text(key, text),
+                    Err(e) if opts.keep_going => {
+                        opts.warn(&format!("warning: cannot read define file {path}: {e}"));
+                    }
+                    Err(e) => {
+                        eprintln!("cannot read define file {path}: {e}");
+                        std::process::exit(1);
                     }
                 }
             }
-            if trimmed.starts_with("else") {
-                let top = stack.last_mut().ok_or_else(|| {
-                    "invalid directive structure: #else without matching #if/#ifdef/#ifndef"
-                        .to_string()
-                })?;
-                if !top.else_seen {
-                    top.else_seen = true;
-                    top.active = !top.active;
-                    current_active = top.parent_active && top.active;
+        }
+    }
+    // -U / --undef come after every define, so they can cancel any of them.
+    for key in &undefs {
+        defs.undefine(key);
+    }
+    opts.include_dirs = canonical_include_dirs(&include_dirs, &opts, warn_missing_include_dirs);
+
+    // `--emit-ast` prints each input's directive structure instead of processing it.
+    if emit_ast {
+        for input in &inputs {
+            let (name, bytes) = if input == "-" {
+                let mut bytes = Vec::new();
+                let read = io::stdin().read_to_end(&mut bytes).map(|_| bytes);
+                (stdin_name.as_str(), read)
+            } else {
+                (input.as_str(), fs::read(input))
+            };
+            let json = bytes
+                .map_err(|e| e.to_string())
+                .and_then(|bytes| opts.input_encoding.decode(bytes))
+                .map_err(|e| Error::new(ErrorKind::Io, format!("cannot read {name}: {e}")))
+                .and_then(|content| directive_ast(Path::new(name), &content, &opts));
+            match json {
+                Ok(json) => println!("{json}"),
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(exit_code(&e));
                 }
-                continue;
-            }
-            if trimmed.starts_with("endif") {
-                let top = stack.pop().ok_or_else(|| {
-                    "invalid directive structure: #endif without matching #if/#ifdef/#ifndef"
-                        .to_string()
-                })?;
-                current_active = top.parent_active;
-                continue;
             }
         }
+        return;
+    }
 
-        if current_active {
-            let replaced = replace_dollar_vars(raw_line, defs);
-            out.push_str(&replaced);
-            out.push('\n');
+    // --out-dir alone keeps each input's file name.
+    if out_dir.is_some() && derived.is_none() {
+        derived = Some(DerivedOutput::AppendSuffix(String::new()));
+    }
+    if let Some(derived) = derived {
+        if output.is_some() {
+            eprintln!("-o cannot be combined with --in-place, --in-place-suffix or --out-dir");
+            std::process::exit(2);
+        }
+        if tree_json.is_some() {
+            eprintln!("--emit-tree-json cannot be combined with --in-place, --in-place-suffix or --out-dir");
+            std::process::exit(2);
+        }
+        if opts.list_includes {
+            eprintln!("--list-includes cannot be combined with --in-place, --in-place-suffix or --out-dir");
+            std::process::exit(2);
         }
+        if let Some(dir) = &out_dir
+            && let Err(e) = fs::create_dir_all(dir)
+        {
+            eprintln!("cannot create {dir}: {e}");
+            std::process::exit(1);
+        }
+        let mut status = 0;
+        for input in &inputs {
+            if input == "-" {
+                eprintln!("cannot derive an output path for stdin ('-')");
+                std::process::exit(2);
+            }
+            let path = derived.output_path(input).map_err(|e| Error::new(ErrorKind::Other, e));
+            let result = path.and_then(|path| {
+                let path = match &out_dir {
+                    Some(dir) => Path::new(dir)
+                        .join(Path::new(&path).file_name().unwrap_or_default())
+                        .to_string_lossy()
+                        .into_owned(),
+                    None => path,
+                };
+                if Path::new(&path) == Path::new(input) {
+                    let message = format!("{input}: output path is the same as the input");
+                    return Err(Error::new(ErrorKind::Other, message));
+                }
+                let mut out = String::new();
+                let mut input_defs = defs.clone();
+                process_file(Path::new(input), &mut input_defs, &mut out, &opts)?;
+                if dump_defines {
+                    eprintln!("{input}:");
+                    print_defines(&input_defs);
+                }
+                if error_if_empty && out.trim().is_empty() {
+                    return Err(Error::new(ErrorKind::Other, format!("{input}: output is empty")));
+                }
+                if print_hash {
+                    eprintln!("{}  {path}", sha256_hex(out.as_bytes()));
+                }
+                fs::write(&path, &out)
+                    .map_err(|e| Error::new(ErrorKind::Io, format!("cannot write {path}: {e}")))
+            });
+            if let Err(e) = result {
+                eprintln!("{e}");
+                if !continue_on_error {
+                    std::process::exit(exit_code(&e));
+                }
+                // With several failures, the first one decides the exit status.
+                if status == 0 {
+                    status = exit_code(&e);
+                }
+            }
+        }
+        if report_errors(&opts) && status == 0 {
+            status = 1;
+        }
+        if status == 0 && fail_on_warning && opts.warning_count() > 0 {
+            status = 1;
+        }
+        if status != 0 {
+            std::process::exit(status);
+        }
+        return;
     }
 
-    if !stack.is_empty() {
-        return Err("invalid directive structure: missing #endif".to_string());
+    // With `--emit-tree-json -` the tree takes the place of the document on stdout.
+    let tree_to_stdout = tree_json.as_deref() == Some("-");
+    // A plain run writes lines to stdout as they are produced. `-o` (written
+    // only on success), `--diff`, `--print-hash`, `--rehydrate` and
+    // `--error-if-empty` need the whole document, and `--list-includes`
+    // replaces it.
+    let stream = output.is_none()
+        && !print_hash
+        && !tree_to_stdout
+        && !rehydrate
+        && !error_if_empty
+        && !opts.list_includes;
+    let mut stdout = io::BufWriter::new(io::stdout().lock());
+    let mut out = String::new();
+    let mut included: Vec<PathBuf> = Vec::new();
+    let mut result = Ok(());
+    // Several inputs are processed in order into one document, sharing `defs`
+    // so earlier files can define variables for later ones.
+    for input in &inputs {
+        result = if input == "-" {
+            // Input from stdin has no directory of its own; includes resolve against the CWD.
+            let mut bytes = Vec::new();
+            io::stdin()
+                .read_to_end(&mut bytes)
+                .map_err(|e| e.to_string())
+                .and_then(|_| opts.input_encoding.decode(bytes))
+                .map_err(|e| Error::new(ErrorKind::Io, format!("cannot read stdin: {e}")))
+                .and_then(|content| {
+                    let path = Path::new(&stdin_name);
+                    if rehydrate {
+                        out.push_str(&rehydrate_content(&content));
+                        Ok(())
+                    } else if check {
+                        process_content_to(path, &content, &mut defs, &mut io::sink(), &opts)
+                    } else if stream {
+                        process_content_to(path, &content, &mut defs, &mut stdout, &opts)
+                    } else {
+                        process_content(path, &content, &mut defs, &mut out, &opts)
+                    }
+                })
+        } else if rehydrate {
+            fs::read_to_string(input)
+                .map(|content| out.push_str(&rehydrate_content(&content)))
+                .map_err(|e| Error::new(ErrorKind::Io, format!("cannot read {input}: {e}")))
+        } else if check {
+            // `--check` only validates, so the document goes nowhere.
+            process_file_to(&PathBuf::from(input), &mut defs, &mut io::sink(), &opts)
+        } else if stream {
+            process_file_to(&PathBuf::from(input), &mut defs, &mut stdout, &opts)
+        } else {
+            process_file(&PathBuf::from(input), &mut defs, &mut out, &opts)
+        };
+        // The include list restarts with each input, so gather it as we go.
+        for path in opts.included_files() {
+            if !included.contains(&path) {
+                included.push(path);
+            }
+        }
+        if result.is_err() {
+            break;
+        }
+    }
+    // Flush even after an error, so whatever was produced before it is kept.
+    let flushed = stdout
+        .flush()
+        .map_err(|e| Error::new(ErrorKind::Io, format!("cannot write output: {e}")));
+    if let Err(e) = result.and(flushed) {
+        eprintln!("{e}");
+        std::process::exit(exit_code(&e));
+    }
+    // With `--list-includes` the list of included files replaces the document.
+    if opts.list_includes {
+        out = included
+            .iter()
+            .map(|path| format!("{}\n", path.display()))
+            .collect();
+    }
+    // All inputs together make one document, so it only counts as empty if
+    // none of them produced anything.
+    if error_if_empty && out.trim().is_empty() {
+        eprintln!("output is empty");
+        std::process::exit(1);
+    }
+    if print_hash {
+        eprintln!("{}", sha256_hex(out.as_bytes()));
+    }
+    if dump_defines {
+        print_defines(&defs);
+    }
+    if let Some(tree_path) = &tree_json
+        && let Some(json) = opts.tree_json()
+    {
+        if tree_to_stdout {
+            println!("{json}");
+        } else if let Err(e) = fs::write(tree_path, format!("{json}\n")) {
+            eprintln!("cannot write {tree_path}: {e}");
+            std::process::exit(1);
+        }
     }
 
-    Ok(())
+    match output {
+        Some(path) if diff => {
+            let existing = fs::read_to_string(&path).unwrap_or_default();
+            let patch = unified_diff(&existing, &out, &path, &path);
+            if !patch.is_empty() {
+                print!("{patch}");
+                std::process::exit(1);
+            }
+        }
+        Some(path) => {
+            if let Err(e) = fs::write(&path, &out) {
+                eprintln!("cannot write {path}: {e}");
+                std::process::exit(1);
+            }
+        }
+        None if tree_to_stdout || stream => {}
+        None => print!("{out}"),
+    }
+    if report_errors(&opts) {
+        std::process::exit(1);
+    }
+    if fail_on_warning && opts.warning_count() > 0 {
+        std::process::exit(1);
+    }
 }
 
-fn directive_args<'a>(line: &'a str, kw: &str) -> Option<&'a str> {
-    let rest = line.strip_prefix(kw)?;
-    if rest.is_empty() || rest.chars().next().is_some_and(|c| c.is_whitespace()) {
-        Some(rest.trim_start())
-    } else {
-        None
+/// The exit status for a failed run: one per [`ErrorKind`], and 1 for errors
+/// of no particular kind.
+fn exit_code(error: &Error) -> i32 {
+    match error.kind() {
+        ErrorKind::Expr => 10,
+        ErrorKind::Structure => 11,
+        ErrorKind::Io => 12,
+        ErrorKind::Cycle => 13,
+        ErrorKind::Other => 1,
     }
 }
 
-fn parse_single_ident(args: &str) -> Option<String> {
-    let name = args.trim();
-    if is_ident(name) {
-        Some(name.to_string())
-    } else {
-        None
+/// Prints the errors collected under `--keep-going` to stderr. Returns
+/// whether there were any.
+fn report_errors(opts: &Options) -> bool {
+    let errors = opts.take_errors();
+    for error in &errors {
+        eprintln!("{error}");
     }
+    !errors.is_empty()
 }
 
-fn parse_define_args(args: &str) -> Option<(String, String)> {
-    let trimmed = args.trim_start();
-    if trimmed.is_empty() {
-        return None;
+/// Prints `KEY=VALUE` to stderr for each variable, or `KEY <undefined>` for
+/// one that was undefined, sorted by name.
+fn print_defines(defs: &Defs) {
+    for (key, value) in defs.iter() {
+        match value {
+            Some(value) => eprintln!("{key}={value}"),
+            None => eprintln!("{key} <undefined>"),
+        }
     }
+}
 
-    if let Some(idx) = trimmed.find(char::is_whitespace) {
-        let (name, value) = trimmed.split_at(idx);
-        if !is_ident(name) {
-            return None;
-        }
-        let value = value.trim_start();
-        if value.is_empty() {
-            Some((name.to_string(), "TRUE".to_string()))
-        } else {
-            Some((name.to_string(), value.to_string()))
+/// Replaces each `@FILE` argument with the arguments read from `FILE`,
+/// recursively. `open` holds the response files being expanded, to detect
+/// cycles.
+fn expand_response_files(
+    args: impl IntoIterator<Item = String>,
+    open: &mut Vec<PathBuf>,
+) -> Result<Vec<String>, String> {
+    let mut expanded = Vec::new();
+    for arg in args {
+        let Some(path) = arg.strip_prefix('@') else {
+            expanded.push(arg);
+            continue;
+        };
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read response file {path}: {e}"))?;
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        if open.contains(&canonical) {
+            let chain: Vec<String> = open
+                .iter()
+                .chain([&canonical])
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(format!("response file cycle detected: {}", chain.join(" -> ")));
         }
-    } else if is_ident(trimmed) {
-        Some((trimmed.to_string(), "TRUE".to_string()))
-    } else {
-        None
+        open.push(canonical);
+        expanded.extend(expand_response_files(split_response_file(&text), open)?);
+        open.pop();
     }
+    Ok(expanded)
 }
 
-fn parse_include_path(rest: &str, defs: &Defs) -> Option<PathBuf> {
-    let after = rest["include".len()..].trim();
-    if after.is_empty() {
-        return None;
-    }
-    let mut cleaned = after.to_string();
-    cleaned.retain(|c| c != '"');
-    let replaced = replace_hash_vars(&cleaned, defs);
-    if replaced.is_empty() {
-        None
-    } else {
-        Some(PathBuf::from(replaced))
+/// Splits response file text on whitespace. Single or double quotes group
+/// text containing whitespace into one argument and are removed.
+fn split_response_file(text: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quote: Option<char> = None;
+    for c in text.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => current.get_or_insert_default().push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                current.get_or_insert_default();
+            }
+            None if c.is_whitespace() => args.extend(current.take()),
+            None => current.get_or_insert_default().push(c),
+        }
     }
+    args.extend(current);
+    args
 }
 
-fn replace_hash_vars(input: &str, defs: &Defs) -> String {
-    let mut out = String::with_capacity(input.len());
-    let mut i = 0;
-    let bytes = input.as_bytes();
-    while i + 1 < bytes.len() {
-        if bytes[i] == b'#' && bytes[i + 1] == b'#' {
-            if let Some(end) = find_double_hash_end(bytes, i + 2) {
-                let name = &input[i + 2..end];
-                if is_ident(name) && defs.is_defined(name) {
-                    out.push_str(&defs.get_value(name));
-                }
-                i = end + 2;
-                continue;
+/// Decodes `\n`, `\t` and `\\` in a `-D` value for `--decode-escapes`.
+/// Any other backslash is kept as it is.
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('\\') => out.push('\\'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
             }
+            None => out.push('\\'),
         }
-        out.push(bytes[i] as char);
-        i += 1;
-    }
-    if i < bytes.len() {
-        out.push(bytes[i] as char);
     }
     out
 }
 
-fn find_double_hash_end(bytes: &[u8], start: usize) -> Option<usize> {
-    let mut j = start;
-    while j + 1 < bytes.len() {
-        if bytes[j] == b'#' && bytes[j + 1] == b'#' {
-            return Some(j);
+/// Defines every environment variable whose name `matches`, with
+/// `strip_prefix` removed from the name. Names that are not valid
+/// identifiers after stripping are skipped.
+fn import_env(defs: &mut Defs, matches: impl Fn(&str) -> bool, strip_prefix: &str) {
+    let mut vars: Vec<(String, String)> = env::vars()
+        .filter(|(name, _)| matches(name))
+        .collect();
+    vars.sort();
+    for (name, value) in vars {
+        let key = name.strip_prefix(strip_prefix).unwrap_or(&name);
+        if is_ident(key) {
+            apply_define(defs, &format!("{key}={value}"));
         }
-        j += 1;
     }
-    None
 }
 
-fn replace_dollar_vars(input: &str, defs: &Defs) -> String {
-    let mut out = String::with_capacity(input.len());
-    let mut i = 0;
-    let bytes = input.as_bytes();
-    while i + 1 < bytes.len() {
-        if bytes[i] == b'$' && bytes[i + 1] == b'$' {
-            if let Some(end) = find_double_dollar_end(bytes, i + 2) {
-                let name = &input[i + 2..end];
-                if is_ident(name) {
-                    out.push_str(&defs.get_value(name));
+/// Canonicalizes the `-I` directories and drops repeats, keeping the first
+/// occurrence so lookup order is unchanged. Directories that do not exist are
+/// skipped, with a warning under `--warn-missing-include-dirs`.
+fn canonical_include_dirs(dirs: &[String], opts: &Options, warn_missing: bool) -> Vec<PathBuf> {
+    let mut canonical: Vec<PathBuf> = Vec::new();
+    for dir in dirs {
+        match fs::canonicalize(dir) {
+            Ok(path) if path.is_dir() => {
+                if !canonical.contains(&path) {
+                    canonical.push(path);
+                }
+            }
+            _ => {
+                if warn_missing {
+                    opts.warn(&format!("warning: include directory '{dir}' does not exist"));
                 }
-                i = end + 2;
-                continue;
             }
         }
-        out.push(bytes[i] as char);
-        i += 1;
-    }
-    if i < bytes.len() {
-        out.push(bytes[i] as char);
     }
-    out
+    canonical
 }
 
-fn find_double_dollar_end(bytes: &[u8], start: usize) -> Option<usize> {
-    let mut j = start;
-    while j + 1 < bytes.len() {
-        if bytes[j] == b'$' && bytes[j + 1] == b'$' {
-            return Some(j);
+/// Expands a `--input-glob` pattern to the sorted list of matching files.
+/// `*` matches any run of characters and `?` a single character within one
+/// path component; names starting with `.` only match a pattern that does too.
+fn glob(pattern: &str) -> Vec<String> {
+    let (mut paths, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec!["/".to_string()], rest),
+        None => (vec![String::new()], pattern),
+    };
+    let join = |base: &str, name: &str| {
+        if base.is_empty() || base.ends_with('/') {
+            format!("{base}{name}")
+        } else {
+            format!("{base}/{name}")
         }
-        j += 1;
-    }
-    None
-}
-
-fn is_ident(s: &str) -> bool {
-    let mut chars = s.chars();
-    match chars.next() {
-        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
-        _ => return false,
-    }
-    for c in chars {
-        if !(c.is_ascii_alphanumeric() || c == '_') {
-            return false;
+    };
+    for segment in rest.split('/').filter(|s| !s.is_empty()) {
+        let segment_chars: Vec<char> = segment.chars().collect();
+        if !segment.contains(['*', '?']) {
+            paths = paths.iter().map(|base| join(base, segment)).collect();
+            continue;
+        }
+        let mut next = Vec::new();
+        for base in &paths {
+            let dir = if base.is_empty() { "." } else { base.as_str() };
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') && !segment.starts_with('.') {
+                    continue;
+                }
+                let name_chars: Vec<char> = name.chars().collect();
+                if wildcard_match(&segment_chars, &name_chars) {
+                    next.push(join(base, &name));
+                }
+            }
         }
+        paths = next;
     }
-    true
+    paths.retain(|path| Path::new(path).is_file());
+    paths.sort();
+    paths
 }
 
-fn truthy(value: &str) -> bool {
-    if value.is_empty() {
-        return false;
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| wildcard_match(rest, &name[skip..])),
+        Some(('?', rest)) => !name.is_empty() && wildcard_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && wildcard_match(rest, &name[1..]),
     }
-    let upper = value.to_ascii_uppercase();
-    !(upper == "0" || upper == "F" || upper == "FALSE" || upper == "NO")
 }
 
-#[derive(Debug, Clone)]
-enum Token {
-    Ident(String),
-    Str(String),
-    Num(String),
-    And,
-    Or,
-    Eq,
-    Ne,
-    Not,
-    LParen,
-    RParen,
+/// A built-in feature check run by `--self-test`. An `expected` output of
+/// `None` means processing must fail.
+struct SelfTestCase {
+    name: &'static str,
+    defines: &'static [(&'static str, &'static str)],
+    input: &'static str,
+    expected: Option<&'static str>,
 }
 
-fn eval_expr(expr: &str, defs: &Defs) -> Result<bool, String> {
-    let tokens = tokenize(expr)?;
-    let mut parser = Parser { tokens: &tokens, pos: 0, defs };
-    let value = parser.parse_or()?;
-    if parser.pos != tokens.len() {
-        return Err(format!("invalid expression: unexpected token at position {}", parser.pos));
-    }
-    Ok(value)
-}
+const SELF_TEST_CASES: &[SelfTestCase] = &[
+    SelfTestCase {
+        name: "substitution",
+        defines: &[("NAME", "Alice")],
+        input: "hi $$NAME$$ $$NOPE$$\n",
+        expected: Some("hi Alice \n"),
+    },
+    SelfTestCase {
+        name: "ifdef",
+        defines: &[("A", "1")],
+        input: "#ifdef A\nyes\n#else\nno\n#endif\n",
+        expected: Some("yes\n"),
+    },
+    SelfTestCase {
+        name: "ifndef",
+        defines: &[],
+        input: "#ifndef A\nyes\n#else\nno\n#endif\n",
+        expected: Some("yes\n"),
+    },
+    SelfTestCase {
+        name: "if-expression",
+        defines: &[("A", "x"), ("B", "0")],
+        input: "#if (A == \"x\" && !B) || C\nyes\n#endif\n",
+        expected: Some("yes\n"),
+    },
+    SelfTestCase {
+        name: "define-undef",
+        defines: &[],
+        input: "#define K v\n$$K$$\n#undef K\n[$$K$$]\n",
+        expected: Some("v\n[]\n"),
+    },
+    SelfTestCase {
+        name: "eval",
+        defines: &[("N", "2")],
+        input: "#eval S = \"n=\" + N + 1\n$$S$$\n",
+        expected: Some("n=21\n"),
+    },
+    SelfTestCase {
+        name: "capture",
+        defines: &[],
+        input: "#capture C\nx\n#endcapture\n$$C$$$$C$$\n",
+        expected: Some("xx\n"),
+    },
+    SelfTestCase {
+        name: "require-defined",
+        defines: &[],
+        input: "#require-defined\n$$NOPE$$\n#end-require-defined\n",
+        expected: None,
+    },
+    SelfTestCase {
+        name: "unmatched-endif",
+        defines: &[],
+        input: "#endif\n",
+        expected: None,
+    },
+    SelfTestCase {
+        name: "missing-endif",
+        defines: &[],
+        input: "#ifdef A\n",
+        expected: None,
+    },
+    SelfTestCase {
+        name: "invalid-expression",
+        defines: &[],
+        input: "#if (A &&)\n#endif\n",
+        expected: None,
+    },
+];
 
-fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
-    let mut tokens = Vec::new();
-    let mut i = 0;
-    let chars: Vec<char> = expr.chars().collect();
-    while i < chars.len() {
-        let c = chars[i];
-        if c.is_whitespace() {
-            i += 1;
-            continue;
+/// Runs the built-in feature checks, reporting each to stderr. Returns
+/// whether all of them passed.
+fn self_test() -> bool {
+    let opts = Options::default();
+    let mut failures = 0;
+    for case in SELF_TEST_CASES {
+        let mut defs = Defs::new();
+        for (key, value) in case.defines {
+            defs.set_defined(key, Some(value.to_string()));
         }
-        match c {
-            '&' => {
-                if i + 1 < chars.len() && chars[i + 1] == '&' {
-                    tokens.push(Token::And);
-                    i += 2;
-                } else {
-                    return Err("invalid expression: single '&'".to_string());
-                }
-            }
-            '|' => {
-                if i + 1 < chars.len() && chars[i + 1] == '|' {
-                    tokens.push(Token::Or);
-                    i += 2;
-                } else {
-                    return Err("invalid expression: single '|'".to_string());
-                }
-            }
-            '=' => {
-                if i + 1 < chars.len() && chars[i + 1] == '=' {
-                    tokens.push(Token::Eq);
-                    i += 2;
-                } else {
-                    return Err("invalid expression: single '='".to_string());
-                }
-            }
-            '!' => {
-                if i + 1 < chars.len() && chars[i + 1] == '=' {
-                    tokens.push(Token::Ne);
-                    i += 2;
-                } else {
-                    tokens.push(Token::Not);
-                    i += 1;
-                }
-            }
-            '(' => {
-                tokens.push(Token::LParen);
-                i += 1;
-            }
-            ')' => {
-                tokens.push(Token::RParen);
-                i += 1;
-            }
-            '"' => {
-                i += 1;
-                let mut s = String::new();
-                while i < chars.len() {
-                    let ch = chars[i];
-                    if ch == '"' {
-                        break;
-                    }
-                    if ch == '\\' && i + 1 < chars.len() {
-                        let next = chars[i + 1];
-                        s.push(next);
-                        i += 2;
-                        continue;
-                    }
-                    s.push(ch);
-                    i += 1;
-                }
-                if i >= chars.len() || chars[i] != '"' {
-                    return Err("invalid expression: unterminated string".to_string());
-                }
-                i += 1;
-                tokens.push(Token::Str(s));
-            }
-            c if c.is_ascii_digit() => {
-                let mut s = String::new();
-                s.push(c);
-                i += 1;
-                while i < chars.len() && chars[i].is_ascii_digit() {
-                    s.push(chars[i]);
-                    i += 1;
-                }
-                tokens.push(Token::Num(s));
-            }
-            c if c.is_ascii_alphabetic() || c == '_' => {
-                let mut s = String::new();
-                s.push(c);
-                i += 1;
-                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
-                    s.push(chars[i]);
-                    i += 1;
-                }
-                tokens.push(Token::Ident(s));
-            }
-            _ => return Err(format!("invalid expression: unexpected char '{c}'")),
+        let mut out = String::new();
+        let result = process_content(
+            Path::new("<self-test>"),
+            case.input,
+            &mut defs,
+            &mut out,
+            &opts,
+        );
+        let passed = match (result, case.expected) {
+            (Ok(()), Some(expected)) => out == expected,
+            (Err(_), None) => true,
+            _ => false,
+        };
+        if passed {
+            eprintln!("self-test: {} ... ok", case.name);
+        } else {
+            failures += 1;
+            eprintln!("self-test: {} ... FAILED", case.name);
         }
     }
-    Ok(tokens)
+    eprintln!(
+        "self-test: {} passed, {} failed",
+        SELF_TEST_CASES.len() - failures,
+        failures
+    );
+    failures == 0
 }
 
-struct Parser<'a> {
-    tokens: &'a [Token],
-    pos: usize,
-    defs: &'a Defs,
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DiffOp {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
 }
 
-impl<'a> Parser<'a> {
-    fn parse_or(&mut self) -> Result<bool, String> {
-        let mut left = self.parse_and()?;
-        while self.match_token(|t| matches!(t, Token::Or)) {
-            let right = self.parse_and()?;
-            left = left || right;
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<DiffOp> {
+    let n = old.len();
+    let m = new.len();
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
         }
-        Ok(left)
     }
 
-    fn parse_and(&mut self) -> Result<bool, String> {
-        let mut left = self.parse_not()?;
-        while self.match_token(|t| matches!(t, Token::And)) {
-            let right = self.parse_not()?;
-            left = left && right;
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old[i] == new[j] {
+            ops.push(DiffOp::Equal(i, j));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            ops.push(DiffOp::Delete(i));
+            i += 1;
+        } else {
+            ops.push(DiffOp::Insert(j));
+            j += 1;
         }
-        Ok(left)
     }
+    ops.extend((i..n).map(DiffOp::Delete));
+    ops.extend((j..m).map(DiffOp::Insert));
+    ops
+}
 
-    fn parse_not(&mut self) -> Result<bool, String> {
-        if self.match_token(|t| matches!(t, Token::Not)) {
-            let v = self.parse_not()?;
-            return Ok(!v);
-        }
-        self.parse_cmp()
+fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    const CONTEXT: usize = 3;
+
+    if old == new {
+        return String::new();
     }
+    // Lines keep their terminators, so a missing final newline is a change.
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let ops = diff_lines(&old_lines, &new_lines);
 
-    fn parse_cmp(&mut self) -> Result<bool, String> {
-        if self.match_token(|t| matches!(t, Token::LParen)) {
-            let v = self.parse_or()?;
-            if !self.match_token(|t| matches!(t, Token::RParen)) {
-                return Err("invalid expression: missing ')'".to_string());
+    let mut out = format!("--- {old_name}\n+++ {new_name}\n");
+    let mut idx = 0;
+    while idx < ops.len() {
+        let Some(first_change) = ops[idx..]
+            .iter()
+            .position(|op| !matches!(op, DiffOp::Equal(..)))
+            .map(|p| idx + p)
+        else {
+            break;
+        };
+        let start = first_change.saturating_sub(CONTEXT).max(idx);
+
+        // Extend the hunk while changes are separated by at most 2 * CONTEXT equal lines.
+        let mut end = first_change;
+        let mut k = first_change;
+        while k < ops.len() {
+            if !matches!(ops[k], DiffOp::Equal(..)) {
+                end = k + 1;
+                k += 1;
+                continue;
+            }
+            let run = ops[k..]
+                .iter()
+                .take_while(|op| matches!(op, DiffOp::Equal(..)))
+                .count();
+            if k + run < ops.len() && run <= 2 * CONTEXT {
+                k += run;
+            } else {
+                break;
             }
-            return Ok(v);
-        }
-        let left = self.parse_value()?;
-        if self.match_token(|t| matches!(t, Token::Eq)) {
-            let right = self.parse_value()?;
-            return Ok(left == right);
         }
-        if self.match_token(|t| matches!(t, Token::Ne)) {
-            let right = self.parse_value()?;
-            return Ok(left != right);
+        let end = (end + CONTEXT).min(ops.len());
+
+        let (mut old_start, mut new_start) = (None, None);
+        let (mut old_count, mut new_count) = (0, 0);
+        let mut body = String::new();
+        for op in &ops[start..end] {
+            match *op {
+                DiffOp::Equal(i, j) => {
+                    old_start.get_or_insert(i);
+                    new_start.get_or_insert(j);
+                    old_count += 1;
+                    new_count += 1;
+                    push_diff_line(&mut body, ' ', old_lines[i]);
+                }
+                DiffOp::Delete(i) => {
+                    old_start.get_or_insert(i);
+                    old_count += 1;
+                    push_diff_line(&mut body, '-', old_lines[i]);
+                }
+                DiffOp::Insert(j) => {
+                    new_start.get_or_insert(j);
+                    new_count += 1;
+                    push_diff_line(&mut body, '+', new_lines[j]);
+                }
+            }
         }
-        Ok(truthy(&left))
+        let old_start = hunk_start(old_start, old_count, &ops[..start], true);
+        let new_start = hunk_start(new_start, new_count, &ops[..start], false);
+        out.push_str(&format!(
+            "@@ -{old_start},{old_count} +{new_start},{new_count} @@\n{body}"
+        ));
+        idx = end;
     }
+    out
+}
 
-    fn parse_value(&mut self) -> Result<String, String> {
-        if let Some(token) = self.tokens.get(self.pos) {
-            let value = match token {
-                Token::Ident(name) => self.defs.get_value(name),
-                Token::Str(s) => s.clone(),
-                Token::Num(n) => n.clone(),
-                _ => return Err("invalid expression: expected value".to_string()),
-            };
-            self.pos += 1;
-            return Ok(value);
-        }
-        Err("invalid expression: unexpected end".to_string())
+/// Appends `line`, with its terminator, to a hunk after `marker`. A last
+/// line without one is followed by the `\ No newline at end of file` note.
+fn push_diff_line(body: &mut String, marker: char, line: &str) {
+    body.push(marker);
+    body.push_str(line);
+    if !line.ends_with('\n') {
+        body.push_str("\n\\ No newline at end of file\n");
     }
+}
 
-    fn match_token<F>(&mut self, pred: F) -> bool
-    where
-        F: Fn(&Token) -> bool,
-    {
-        if let Some(tok) = self.tokens.get(self.pos) {
-            if pred(tok) {
-                self.pos += 1;
-                return true;
-            }
+fn hunk_start(first: Option<usize>, count: usize, before: &[DiffOp], old_side: bool) -> usize {
+    match first {
+        Some(line) => line + 1,
+        None => {
+            // An empty side starts at the line preceding the hunk.
+            let preceding = before
+                .iter()
+                .filter(|op| match op {
+                    DiffOp::Equal(..) => true,
+                    DiffOp::Delete(_) => old_side,
+                    DiffOp::Insert(_) => !old_side,
+                })
+                .count();
+            if count == 0 { preceding } else { preceding + 1 }
         }
-        false
     }
 }
@@ -0,0 +1,234 @@
+//! A small backtracking regular expression matcher for `--import-env-regex`.
+//!
+//! Only the subset needed for matching names is supported:
+//!
+//! - literal characters, and `.` for any character;
+//! - `[...]` and `[^...]` classes of characters and `a-z` ranges, whose
+//!   contents are taken literally (a `]` right after the `[` or `[^` is part of
+//!   the class);
+//! - `\` escapes of punctuation, e.g. `\.` or `\$`, matching it literally;
+//! - the greedy `*`, `+` and `?` quantifiers after a single atom;
+//! - `^` at the start and `$` at the end, anchoring the match.
+//!
+//! Anything else that has a meaning in fuller regex dialects is rejected rather
+//! than matched literally: groups and alternation (`(`, `)`, `|`), counted
+//! repetition (`{`, `}`), `^` or `$` elsewhere in the pattern, and escapes of
+//! letters and digits such as `\d` or `\w`.
+
+#[derive(Debug)]
+pub(crate) struct Regex {
+    nodes: Vec<(RegexAtom, RegexRepeat)>,
+    anchored_start: bool,
+    anchored_end: bool,
+}
+
+#[derive(Debug)]
+enum RegexAtom {
+    Char(char),
+    Any,
+    Class { negated: bool, ranges: Vec<(char, char)> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RegexRepeat {
+    One,
+    ZeroOrOne,
+    ZeroOrMore,
+    OneOrMore,
+}
+
+impl Regex {
+    pub(crate) fn new(pattern: &str) -> Result<Self, String> {
+        let mut chars: Vec<char> = pattern.chars().collect();
+        let anchored_start = chars.first() == Some(&'^');
+        if anchored_start {
+            chars.remove(0);
+        }
+        let anchored_end = chars.last() == Some(&'$') && !ends_with_escape(&chars);
+        if anchored_end {
+            chars.pop();
+        }
+
+        let mut nodes = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let atom = match chars[i] {
+                '.' => RegexAtom::Any,
+                '\\' => {
+                    i += 1;
+                    match chars.get(i) {
+                        Some(c) if c.is_ascii_alphanumeric() => {
+                            return Err(format!("unsupported escape '\\{c}'"));
+                        }
+                        Some(&c) => RegexAtom::Char(c),
+                        None => return Err("trailing '\\'".to_string()),
+                    }
+                }
+                '[' => {
+                    i += 1;
+                    let negated = chars.get(i) == Some(&'^');
+                    if negated {
+                        i += 1;
+                    }
+                    let mut ranges = Vec::new();
+                    while i < chars.len() && (chars[i] != ']' || ranges.is_empty()) {
+                        let lo = chars[i];
+                        if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|&c| c != ']') {
+                            ranges.push((lo, chars[i + 2]));
+                            i += 3;
+                        } else {
+                            ranges.push((lo, lo));
+                            i += 1;
+                        }
+                    }
+                    if i >= chars.len() {
+                        return Err("unterminated '['".to_string());
+                    }
+                    RegexAtom::Class { negated, ranges }
+                }
+                '*' | '+' | '?' => return Err(format!("nothing to repeat before '{}'", chars[i])),
+                '(' | ')' | '|' | '{' | '}' | '^' | '$' => {
+                    let c = chars[i];
+                    return Err(format!("unsupported '{c}' (escape it as '\\{c}' to match literally)"));
+                }
+                c => RegexAtom::Char(c),
+            };
+            i += 1;
+            let repeat = match chars.get(i) {
+                Some('*') => RegexRepeat::ZeroOrMore,
+                Some('+') => RegexRepeat::OneOrMore,
+                Some('?') => RegexRepeat::ZeroOrOne,
+                _ => RegexRepeat::One,
+            };
+            if repeat != RegexRepeat::One {
+                i += 1;
+            }
+            nodes.push((atom, repeat));
+        }
+        Ok(Self { nodes, anchored_start, anchored_end })
+    }
+
+    pub(crate) fn is_match(&self, text: &str) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        if self.anchored_start {
+            return self.match_here(0, &chars, 0);
+        }
+        (0..=chars.len()).any(|start| self.match_here(0, &chars, start))
+    }
+
+    fn match_here(&self, node: usize, text: &[char], pos: usize) -> bool {
+        let Some((atom, repeat)) = self.nodes.get(node) else {
+            return !self.anchored_end || pos == text.len();
+        };
+        let (min, max) = match repeat {
+            RegexRepeat::One => (1, 1),
+            RegexRepeat::ZeroOrOne => (0, 1),
+            RegexRepeat::ZeroOrMore => (0, usize::MAX),
+            RegexRepeat::OneOrMore => (1, usize::MAX),
+        };
+        let available = text[pos..]
+            .iter()
+            .take(max)
+            .take_while(|&&c| atom.matches(c))
+            .count();
+        (min..=available)
+            .rev()
+            .any(|count| self.match_here(node + 1, text, pos + count))
+    }
+}
+
+impl RegexAtom {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Self::Char(expected) => c == *expected,
+            Self::Any => true,
+            Self::Class { negated, ranges } => {
+                ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated
+            }
+        }
+    }
+}
+
+/// Whether the final character of `chars` is escaped by a preceding `\\`.
+fn ends_with_escape(chars: &[char]) -> bool {
+    let backslashes = chars[..chars.len() - 1]
+        .iter()
+        .rev()
+        .take_while(|&&c| c == '\\')
+        .count();
+    backslashes % 2 == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Regex;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Regex::new(pattern).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn unanchored_patterns_match_anywhere() {
+        assert!(matches("APP", "MY_APP_NAME"));
+        assert!(!matches("APP", "MY_AP_NAME"));
+        assert!(matches("", "anything"));
+    }
+
+    #[test]
+    fn anchors_pin_the_start_and_end() {
+        assert!(matches("^APP_", "APP_NAME"));
+        assert!(!matches("^APP_", "MY_APP_NAME"));
+        assert!(matches("_NAME$", "APP_NAME"));
+        assert!(!matches("_NAME$", "APP_NAME_2"));
+        assert!(matches("^$", ""));
+        assert!(!matches("^$", "x"));
+    }
+
+    #[test]
+    fn quantifiers_and_dot() {
+        assert!(matches("^A.C$", "ABC"));
+        assert!(!matches("^A.C$", "AC"));
+        assert!(matches("^AB*C$", "AC"));
+        assert!(matches("^AB*C$", "ABBBC"));
+        assert!(!matches("^AB+C$", "AC"));
+        assert!(matches("^AB+C$", "ABBC"));
+        assert!(matches("^AB?C$", "AC"));
+        assert!(!matches("^AB?C$", "ABBC"));
+        assert!(matches("^A.*Z$", "A_LONG_Z"));
+    }
+
+    #[test]
+    fn classes_ranges_and_negation() {
+        assert!(matches("^[A-Z_]+$", "APP_NAME"));
+        assert!(!matches("^[A-Z_]+$", "App_Name"));
+        assert!(matches("^[^0-9]+$", "NAME"));
+        assert!(!matches("^[^0-9]+$", "NAME2"));
+        assert!(matches("^[]x]$", "]"));
+        assert!(matches("^[a-]$", "-"));
+    }
+
+    #[test]
+    fn escapes_match_punctuation_literally() {
+        assert!(matches("^A\\.B$", "A.B"));
+        assert!(!matches("^A\\.B$", "AxB"));
+        assert!(matches("^A\\$$", "A$"));
+        assert!(matches("^\\(X\\)$", "(X)"));
+    }
+
+    #[test]
+    fn rejects_malformed_patterns() {
+        assert_eq!(Regex::new("[A-Z").unwrap_err(), "unterminated '['");
+        assert_eq!(Regex::new("A\\").unwrap_err(), "trailing '\\'");
+        assert_eq!(Regex::new("*A").unwrap_err(), "nothing to repeat before '*'");
+    }
+
+    #[test]
+    fn rejects_unsupported_syntax() {
+        for pattern in ["(A|B)", "A|B", "A{2}", "A^B", "A$B", "^(APP)_"] {
+            let err = Regex::new(pattern).unwrap_err();
+            assert!(err.starts_with("unsupported '"), "{pattern}: {err}");
+        }
+        assert_eq!(Regex::new("\\d+").unwrap_err(), "unsupported escape '\\d'");
+        assert_eq!(Regex::new("\\w").unwrap_err(), "unsupported escape '\\w'");
+    }
+}
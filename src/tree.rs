@@ -0,0 +1,104 @@
+use std::cell::RefCell;
+
+/// Records which files were processed, what they included, and which
+/// conditional branches were taken, for `--emit-tree-json`.
+#[derive(Debug, Default)]
+pub(crate) struct TreeRecorder {
+    /// Files currently being processed, innermost last.
+    open: RefCell<Vec<FileNode>>,
+    /// The most recently finished top-level file.
+    root: RefCell<Option<FileNode>>,
+}
+
+#[derive(Debug)]
+struct FileNode {
+    path: String,
+    children: Vec<Node>,
+}
+
+#[derive(Debug)]
+enum Node {
+    File(FileNode),
+    Branch {
+        line: usize,
+        directive: String,
+        taken: bool,
+    },
+}
+
+impl TreeRecorder {
+    pub(crate) fn enter(&self, path: &str) {
+        self.open.borrow_mut().push(FileNode {
+            path: path.to_string(),
+            children: Vec::new(),
+        });
+    }
+
+    pub(crate) fn leave(&self) {
+        let mut open = self.open.borrow_mut();
+        let Some(node) = open.pop() else {
+            return;
+        };
+        match open.last_mut() {
+            Some(parent) => parent.children.push(Node::File(node)),
+            None => *self.root.borrow_mut() = Some(node),
+        }
+    }
+
+    pub(crate) fn branch(&self, line: usize, directive: &str, taken: bool) {
+        if let Some(file) = self.open.borrow_mut().last_mut() {
+            file.children.push(Node::Branch {
+                line,
+                directive: directive.to_string(),
+                taken,
+            });
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> Option<String> {
+        let root = self.root.borrow();
+        let mut out = String::new();
+        write_file_node(&mut out, root.as_ref()?);
+        Some(out)
+    }
+}
+
+fn write_file_node(out: &mut String, node: &FileNode) {
+    out.push_str("{\"file\":");
+    write_json_string(out, &node.path);
+    out.push_str(",\"children\":[");
+    for (i, child) in node.children.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        match child {
+            Node::File(file) => write_file_node(out, file),
+            Node::Branch {
+                line,
+                directive,
+                taken,
+            } => {
+                out.push_str(&format!("{{\"line\":{line},\"directive\":"));
+                write_json_string(out, directive);
+                out.push_str(&format!(",\"taken\":{taken}}}"));
+            }
+        }
+    }
+    out.push_str("]}");
+}
+
+pub(crate) fn write_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
@@ -0,0 +1,112 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use textpp::{Defs, ErrorKind, Options, apply_define, preprocess, process_file_to};
+
+fn temp_dir() -> PathBuf {
+    let mut dir = env::temp_dir();
+    dir.push(format!("textpp_library_test_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn preprocess_renders_file_with_programmatic_defines() {
+    let dir = temp_dir();
+    fs::write(dir.join("part.md"), "part for $$NAME$$\n").unwrap();
+    let input = dir.join("doc.md");
+    fs::write(
+        &input,
+        "#ifdef DRAFT\ndraft\n#endif\nHello $$NAME$$\n#include \"part.md\"\n#define NAME changed\n",
+    )
+    .unwrap();
+    let mut defs = Defs::new();
+    defs.define("DRAFT", "1");
+    defs.define("NAME", "Alice");
+    defs.undefine("DRAFT");
+
+    let out = preprocess(&input, &defs, &Options::default()).unwrap();
+
+    assert_eq!(out, "Hello Alice\npart for Alice\n");
+    assert_eq!(defs.get_value("NAME"), "Alice");
+}
+
+#[test]
+fn preprocess_reports_unreadable_input() {
+    let dir = temp_dir();
+
+    let err = preprocess(&dir.join("missing.md"), &Defs::new(), &Options::default()).unwrap_err();
+
+    assert!(err.to_string().starts_with("cannot read "), "{err}");
+    assert_eq!(err.kind(), ErrorKind::Io);
+}
+
+#[test]
+fn process_file_to_streams_into_writer() {
+    let dir = temp_dir();
+    let input = dir.join("stream.md");
+    fs::write(&input, "#define NAME Bob\nHello $$NAME$$\n#if NAME between 1 and 2\n#endif\n")
+        .unwrap();
+    let mut sink: Vec<u8> = Vec::new();
+
+    let err = process_file_to(&input, &mut Defs::new(), &mut sink, &Options::default())
+        .unwrap_err();
+
+    assert!(err.to_string().contains("'between' expects a number"), "{err}");
+    assert_eq!(err.kind(), ErrorKind::Expr);
+    assert_eq!(String::from_utf8(sink).unwrap(), "Hello Bob\n");
+}
+
+#[test]
+fn defs_lookup_distinguishes_undefined_empty_and_valued() {
+    let mut defs = Defs::new();
+    defs.define("EMPTY", "");
+    defs.define("NAME", "Alice");
+    defs.define("GONE", "x");
+    defs.undefine("GONE");
+
+    assert_eq!(defs.lookup("MISSING"), None);
+    assert_eq!(defs.lookup("GONE"), None);
+    assert_eq!(defs.lookup("EMPTY"), Some(""));
+    assert_eq!(defs.lookup("NAME"), Some("Alice"));
+    assert_eq!(defs.get_value("EMPTY"), defs.get_value("MISSING"));
+}
+
+#[test]
+fn defs_is_truthy_follows_cli_defines() {
+    let mut defs = Defs::new();
+    apply_define(&mut defs, "ZERO=0");
+    apply_define(&mut defs, "EMPTY=");
+    apply_define(&mut defs, "YES=yes");
+
+    assert!(defs.is_defined("ZERO"));
+    assert!(!defs.is_truthy("ZERO"));
+    assert!(!defs.is_defined("EMPTY"));
+    assert!(!defs.is_truthy("EMPTY"));
+    assert!(defs.is_truthy("YES"));
+}
+
+#[test]
+fn defs_from_args_applies_define_rules_and_returns_the_rest() {
+    let args = ["-DFLAG", "-DEMPTY=", "-DNAME=val", "-o", "out.md", "-UGONE", "-DGONE=1", "doc.md"];
+
+    let (defs, rest) = Defs::from_args(args.map(String::from));
+
+    assert_eq!(defs.lookup("FLAG"), Some("TRUE"));
+    assert_eq!(defs.lookup("EMPTY"), None);
+    assert_eq!(defs.lookup("NAME"), Some("val"));
+    assert_eq!(defs.lookup("GONE"), None);
+    assert_eq!(rest, ["-o", "out.md", "doc.md"]);
+}
+
+#[test]
+fn define_text_drops_final_terminator_and_leaves_empty_text_undefined() {
+    let mut defs = Defs::new();
+
+    defs.define_text("BODY", "line 1\r\nline 2\r\n".to_string());
+    defs.define_text("EMPTY", "\n".to_string());
+
+    assert_eq!(defs.lookup("BODY"), Some("line 1\r\nline 2"));
+    assert!(!defs.is_defined("EMPTY"));
+}
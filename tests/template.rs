@@ -0,0 +1,77 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use textpp::{Defs, Template};
+
+fn temp_dir() -> PathBuf {
+    let mut dir = env::temp_dir();
+    dir.push(format!("textpp_template_test_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn defs(pairs: &[(&str, &str)]) -> Defs {
+    let mut defs = Defs::new();
+    for (key, value) in pairs {
+        defs.set_defined(key, Some(value.to_string()));
+    }
+    defs
+}
+
+#[test]
+fn renders_same_template_with_different_defines() {
+    let template = Template::compile(
+        "#ifdef PRO\nEdition: Pro $$VERSION$$\n#else\nEdition: Free $$VERSION$$\n#endif\n",
+        ".",
+    );
+
+    let pro = template.render(&defs(&[("PRO", "1"), ("VERSION", "2.0")])).unwrap();
+    let free = template.render(&defs(&[("VERSION", "1.5")])).unwrap();
+
+    assert_eq!(pro, "Edition: Pro 2.0\n");
+    assert_eq!(free, "Edition: Free 1.5\n");
+}
+
+#[test]
+fn render_does_not_modify_caller_defines() {
+    let template = Template::compile("#define NAME inner\n$$NAME$$\n", ".");
+    let outer = defs(&[("NAME", "outer")]);
+
+    assert_eq!(template.render(&outer).unwrap(), "inner\n");
+    assert_eq!(outer.get_value("NAME"), "outer");
+}
+
+#[test]
+fn resolves_includes_against_base_dir() {
+    let dir = temp_dir();
+    fs::write(dir.join("part.md"), "part $$X$$\n").unwrap();
+    let template = Template::compile("#include \"part.md\"\n", &dir);
+
+    assert_eq!(template.render(&defs(&[("X", "a")])).unwrap(), "part a\n");
+    assert_eq!(template.render(&defs(&[("X", "b")])).unwrap(), "part b\n");
+}
+
+#[test]
+fn render_reports_errors() {
+    let template = Template::compile("#if X between 1 and 5\nin range\n#endif\n", ".");
+
+    assert_eq!(template.render(&defs(&[("X", "3")])).unwrap(), "in range\n");
+    let err = template.render(&defs(&[("X", "many")])).unwrap_err();
+    assert!(err.to_string().contains("'between' expects a number"), "{err}");
+}
+
+#[test]
+fn compiled_template_renders_repeatedly() {
+    let template = Template::compile(
+        "#for ITEM in a b c\n#if ITEM == SELECTED\n* $$ITEM$$\n#else\n- $$ITEM$$\n#endif\n#endfor\n",
+        ".",
+    );
+
+    for i in 0..1000 {
+        let selected = ["a", "b", "c"][i % 3];
+        let out = template.render(&defs(&[("SELECTED", selected)])).unwrap();
+        assert_eq!(out.lines().count(), 3);
+        assert!(out.contains(&format!("* {selected}\n")), "{out}");
+    }
+}
@@ -1,7 +1,8 @@
 use std::env;
 use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 static COUNTER: AtomicUsize = AtomicUsize::new(0);
@@ -28,6 +29,31 @@ fn run_textpp(args: &[&str]) -> std::process::Output {
         .unwrap()
 }
 
+fn run_textpp_with_stdin(args: &[&str], stdin: &str) -> std::process::Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_textpp"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn run_textpp_with_env(args: &[&str], vars: &[(&str, &str)]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_textpp"))
+        .args(args)
+        .envs(vars.iter().copied())
+        .output()
+        .unwrap()
+}
+
 fn fixture_path(rel: &str) -> PathBuf {
     PathBuf::from(env!("CARGO_MANIFEST_DIR"))
         .join("tests/fixtures")
@@ -151,6 +177,72 @@ fn invalid_expression_fails() {
     assert!(String::from_utf8_lossy(&out.stderr).contains("invalid expression"));
 }
 
+#[test]
+fn ifdef_expands_variables_in_symbol_name() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#ifdef FEATURE_$$NAME$$\non\n#endif\n#ifndef FEATURE_$$NAME$$\noff\n#endif\n",
+    );
+
+    let on = run_textpp(&["-DNAME=FOO", "-DFEATURE_FOO=1", input.to_str().unwrap()]);
+    let off = run_textpp(&["-DNAME=BAR", "-DFEATURE_FOO=1", input.to_str().unwrap()]);
+    let invalid = run_textpp(&["-DNAME=a-b", input.to_str().unwrap()]);
+
+    assert_eq!(String::from_utf8_lossy(&on.stdout), "on\n");
+    assert_eq!(String::from_utf8_lossy(&off.stdout), "off\n");
+    assert!(!invalid.status.success());
+    let stderr = String::from_utf8_lossy(&invalid.stderr);
+    assert!(
+        stderr.contains("#ifdef expects a variable name, got 'FEATURE_a-b'"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn exit_code_tells_expression_errors_from_structure_errors() {
+    let dir = temp_dir();
+    let expr = dir.join("expr.md");
+    let structure = dir.join("structure.md");
+    let cycle = dir.join("cycle.md");
+    write_file(&expr, "#if (VAR &&)\nX\n#endif\n");
+    write_file(&structure, "#if VAR\nX\n");
+    write_file(&cycle, "#include \"cycle.md\"\n");
+
+    let expr_out = run_textpp(&[expr.to_str().unwrap()]);
+    let structure_out = run_textpp(&[structure.to_str().unwrap()]);
+    let cycle_out = run_textpp(&[cycle.to_str().unwrap()]);
+    let missing_out = run_textpp(&[dir.join("missing.md").to_str().unwrap(), "--rehydrate"]);
+
+    assert_eq!(expr_out.status.code(), Some(10));
+    assert!(String::from_utf8_lossy(&expr_out.stderr).contains("invalid expression"));
+    assert_eq!(structure_out.status.code(), Some(11));
+    assert!(String::from_utf8_lossy(&structure_out.stderr).contains("missing #endif"));
+    assert_eq!(cycle_out.status.code(), Some(13));
+    assert_eq!(missing_out.status.code(), Some(12));
+}
+
+#[test]
+fn exit_code_ignores_markers_in_error_directive_messages() {
+    let dir = temp_dir();
+    let io = dir.join("io.md");
+    let cycle = dir.join("cycle.md");
+    let structure = dir.join("structure.md");
+    write_file(&io, "#error cannot read x\n");
+    write_file(&cycle, "#error \"include cycle detected here\"\n");
+    write_file(&structure, "#error invalid directive structure\n");
+
+    let io_out = run_textpp(&[io.to_str().unwrap()]);
+    let cycle_out = run_textpp(&[cycle.to_str().unwrap()]);
+    let structure_out = run_textpp(&[structure.to_str().unwrap()]);
+
+    assert_eq!(io_out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&io_out.stderr).contains("io.md:1: error: cannot read x"));
+    assert_eq!(cycle_out.status.code(), Some(1));
+    assert_eq!(structure_out.status.code(), Some(1));
+}
+
 #[test]
 fn unmatched_else_fails() {
     let dir = temp_dir();
@@ -163,6 +255,19 @@ fn unmatched_else_fails() {
     assert!(String::from_utf8_lossy(&out.stderr).contains("invalid directive structure"));
 }
 
+#[test]
+fn duplicate_else_fails() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#ifdef A\none\n#else\ntwo\n#else\nthree\n#endif\n");
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("input.md:5: invalid directive structure: duplicate #else"), "{stderr}");
+}
+
 #[test]
 fn unmatched_endif_fails() {
     let dir = temp_dir();
@@ -202,6 +307,127 @@ fn directives_require_hash_at_column_zero() {
     );
 }
 
+#[test]
+fn allow_indented_directives_recognizes_indented_if() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "- item\n  #if VAL == 1\n  - one\n  #else\n  - other\n  #endif\n");
+
+    let strict = run_textpp(&["-DVAL=1", input.to_str().unwrap()]);
+    let indented = run_textpp(&["--allow-indented-directives", "-DVAL=1", input.to_str().unwrap()]);
+
+    assert_eq!(
+        String::from_utf8_lossy(&strict.stdout),
+        "- item\n  #if VAL == 1\n  - one\n  #else\n  - other\n  #endif\n"
+    );
+    assert!(indented.status.success());
+    assert_eq!(String::from_utf8_lossy(&indented.stdout), "- item\n  - one\n");
+}
+
+#[test]
+fn directive_prefix_replaces_hash() {
+    let dir = temp_dir();
+    let input = dir.join("input.c");
+    write_file(
+        &input,
+        "#include <stdio.h>\n//#if X\nint x = $$X$$;\n//#else\nint x = 0;\n//#endif\n#if DEBUG\n#endif\n",
+    );
+
+    let out = run_textpp(&["--directive-prefix", "//#", "-DX=1", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "#include <stdio.h>\nint x = 1;\n#if DEBUG\n#endif\n"
+    );
+}
+
+#[test]
+fn ifeq_and_ifneq_compare_expanded_operands() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    let broken = dir.join("broken.md");
+    write_file(
+        &input,
+        "#ifeq ($$MODE$$, \"prod\")\nlive\n#else\ntest\n#endif\n#ifneq (\"$$MODE$$-x\", \"a, b\")\nother\n#endif\n",
+    );
+    write_file(&broken, "#ifeq MODE, \"prod\"\nlive\n#endif\n");
+
+    let prod = run_textpp(&["-DMODE=prod", input.to_str().unwrap()]);
+    let dev = run_textpp(&["-DMODE=dev", input.to_str().unwrap()]);
+    let malformed = run_textpp(&["-DMODE=prod", broken.to_str().unwrap()]);
+
+    assert!(prod.status.success());
+    assert_eq!(String::from_utf8_lossy(&prod.stdout), "live\nother\n");
+    assert_eq!(String::from_utf8_lossy(&dev.stdout), "test\nother\n");
+    assert_eq!(malformed.status.code(), Some(11));
+    let stderr = String::from_utf8_lossy(&malformed.stderr);
+    assert!(
+        stderr.contains("broken.md:1: invalid directive structure: #ifeq expects '(A, B)'"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn ifeq_operands_are_literal_text_after_expansion() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#ifneq ($$MODE$$, prod)\nnot prod\n#endif\n#ifeq (MODE, prod)\nnames match\n#endif\nend\n",
+    );
+
+    let out = run_textpp(&["-DMODE=prod", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "end\n");
+}
+
+#[test]
+fn ifeq_accepts_no_space_before_parenthesis() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#ifeq($$MODE$$,prod)\nlive\n#endif\n#ifneq($$MODE$$,prod)\nother\n#endif\n",
+    );
+
+    let out = run_textpp(&["-DMODE=prod", input.to_str().unwrap()]);
+
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "live\n");
+}
+
+#[test]
+fn comment_directives_are_dropped() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#// note for authors\nkept\n#; another note\n#ifdef NOPE\n#// hidden note\n#endif\n",
+    );
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "kept\n");
+}
+
+#[test]
+fn preserve_directives_marks_directive_lines_only() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#if FOO\nfoo\n#define X 1\n#else\nbar\n#endif\n");
+
+    let out = run_textpp(&["--preserve-directives", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "<!-- textpp: #if FOO -->\n<!-- textpp: #define X 1 -->\n<!-- textpp: #else -->\nbar\n<!-- textpp: #endif -->\n"
+    );
+}
+
 #[test]
 fn unknown_directives_are_ignored_and_preserved() {
     let dir = temp_dir();
@@ -217,6 +443,23 @@ fn unknown_directives_are_ignored_and_preserved() {
     );
 }
 
+#[test]
+fn strict_directives_rejects_misspelled_directives_only() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#hashtag text\n#1 priority\n# Heading\n#ifdff X\nx\n#endif\n");
+
+    let out = run_textpp(&["--strict-directives", input.to_str().unwrap()]);
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("input.md:4: invalid directive: unknown directive '#ifdff'"), "{stderr}");
+
+    write_file(&input, "#hashtag text\n#1 priority\n# Heading\n#ifdef X\nx\n#endif\n");
+    let out = run_textpp(&["--strict-directives", input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "#hashtag text\n#1 priority\n# Heading\n");
+}
+
 #[test]
 fn define_sets_value_for_later_substitution() {
     let dir = temp_dir();
@@ -322,6 +565,34 @@ fn fixture_basic() {
     run_fixture("valid/basic.md", &["-DNAME=Alice"], "valid/basic.out");
 }
 
+#[test]
+fn fixture_crlf_kept_with_preserve_eol() {
+    run_fixture("valid/crlf.md", &["--preserve-eol", "-DNAME=x"], "valid/crlf.out");
+}
+
+#[test]
+fn fixture_latin1_transcoded_with_input_encoding() {
+    run_fixture(
+        "valid/latin1.md",
+        &["--input-encoding", "latin1", "-DNAME=Zoë"],
+        "valid/latin1.out",
+    );
+}
+
+#[test]
+fn latin1_input_without_input_encoding_fails() {
+    let input = fixture_path("valid/latin1.md");
+
+    let out = run_textpp(&["-DNAME=x", input.to_str().unwrap()]);
+
+    assert_eq!(out.status.code(), Some(12));
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let expected = format!("cannot read {}: not valid UTF-8", input.display());
+    assert!(stderr.contains(&expected), "{stderr}");
+    assert!(stderr.contains("--input-encoding"), "{stderr}");
+}
+
 #[test]
 fn fixture_include_hash() {
     run_fixture(
@@ -345,3 +616,2353 @@ fn fixture_expr() {
         "valid/expr.out",
     );
 }
+
+#[test]
+fn require_defined_region_rejects_undefined_vars() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "outside: $$NOPE$$\n#require-defined\ninside: $$NOPE$$\n#end-require-defined\n",
+    );
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("input.md:3:"));
+    assert!(stderr.contains("undefined variable 'NOPE'"));
+}
+
+#[test]
+fn require_defined_region_allows_defined_vars() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "outside: $$NOPE$$\n#require-defined\ninside: $$VAL$$\n#end-require-defined\nafter: $$NOPE$$\n",
+    );
+
+    let out = run_textpp(&["-DVAL=1", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "outside: \ninside: 1\nafter: \n"
+    );
+}
+
+#[test]
+fn diff_reports_changes_against_existing_output() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    let existing = dir.join("output.txt");
+    write_file(&input, "hello $$NAME$$\n");
+    write_file(&existing, "hello Bob\n");
+
+    let out = run_textpp(&[
+        "-DNAME=Alice",
+        "-o",
+        existing.to_str().unwrap(),
+        "--diff",
+        input.to_str().unwrap(),
+    ]);
+
+    assert!(!out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("@@ -1,1 +1,1 @@\n-hello Bob\n+hello Alice\n"));
+    assert_eq!(fs::read_to_string(&existing).unwrap(), "hello Bob\n");
+}
+
+#[test]
+fn diff_shows_a_missing_final_newline() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    let existing = dir.join("out.txt");
+    write_file(&input, "a\nb\n");
+    write_file(&existing, "a\nb");
+
+    let out = run_textpp(&["-o", existing.to_str().unwrap(), "--diff", input.to_str().unwrap()]);
+
+    assert_eq!(out.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        stdout.ends_with("@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+b\n"),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn diff_is_empty_when_output_is_up_to_date() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    let existing = dir.join("output.txt");
+    write_file(&input, "hello $$NAME$$\n");
+    write_file(&existing, "hello Alice\n");
+
+    let out = run_textpp(&[
+        "-DNAME=Alice",
+        "-o",
+        existing.to_str().unwrap(),
+        "--diff",
+        input.to_str().unwrap(),
+    ]);
+
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+}
+
+#[test]
+fn capture_stores_processed_lines_for_reuse() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#capture BLOCK\nname: $$NAME$$\n#ifdef NAME\nhas name\n#endif\n#endcapture\nstart\n$$BLOCK$$\n--\n$$BLOCK$$\n",
+    );
+
+    let out = run_textpp(&["-DNAME=Alice", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "start\nname: Alice\nhas name\n--\nname: Alice\nhas name\n"
+    );
+}
+
+#[test]
+fn missing_endcapture_fails() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#capture BLOCK\nX\n");
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("missing #endcapture"));
+}
+
+#[test]
+fn mixed_line_endings_fail_under_error_policy() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "one\r\ntwo\nthree\r\n");
+
+    let out = run_textpp(&["--line-endings=error", input.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("mixed line endings"));
+}
+
+#[test]
+fn mixed_line_endings_round_trip_under_preserve_policy() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "one $$VAL$$\r\ntwo\nthree\r\nfour");
+
+    let out = run_textpp(&["--line-endings=preserve", "-DVAL=1", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "one 1\r\ntwo\nthree\r\nfour"
+    );
+}
+
+#[test]
+fn mixed_line_endings_normalize_to_crlf() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "one\r\ntwo\n");
+
+    let out = run_textpp(&["--line-endings=crlf", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "one\r\ntwo\r\n");
+}
+
+#[test]
+fn eval_concatenates_strings() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#eval GREETING = \"Hello, \" + NAME + \"!\"\n$$GREETING$$\n",
+    );
+
+    let out = run_textpp(&["-DNAME=Alice", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "Hello, Alice!\n");
+}
+
+#[test]
+fn plus_adds_numbers_and_coerces_mixed_operands() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#eval SUM = N + 2\n#eval LABEL = \"item-\" + N + 2\nsum=$$SUM$$ label=$$LABEL$$\n#if N + 1 == 4\nmatch\n#endif\n",
+    );
+
+    let out = run_textpp(&["-DN=3", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "sum=5 label=item-32\nmatch\n"
+    );
+}
+
+#[test]
+fn use_imports_defines_without_emitting_content() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    let vars = dir.join("vars.md");
+    write_file(
+        &vars,
+        "This prose is ignored.\n#define SITE example.org\n#ifdef PROD\n#define MODE production\n#endif\n",
+    );
+    write_file(&input, "#use \"vars.md\"\nsite: $$SITE$$ mode: $$MODE$$\n");
+
+    let out = run_textpp(&["-DPROD", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "site: example.org mode: production\n"
+    );
+}
+
+#[test]
+fn self_test_passes() {
+    let out = run_textpp(&["--self-test"]);
+
+    assert!(out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("self-test: ifdef ... ok"));
+    assert!(!stderr.contains("FAILED"));
+}
+
+#[test]
+fn in_place_suffix_appends_to_each_input_name() {
+    let dir = temp_dir();
+    let first = dir.join("first.md");
+    let second = dir.join("second.md");
+    write_file(&first, "first $$VAL$$\n");
+    write_file(&second, "second $$VAL$$\n");
+
+    let out = run_textpp(&[
+        "-DVAL=1",
+        "--in-place-suffix",
+        ".out",
+        first.to_str().unwrap(),
+        second.to_str().unwrap(),
+    ]);
+
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+    assert_eq!(fs::read_to_string(dir.join("first.md.out")).unwrap(), "first 1\n");
+    assert_eq!(fs::read_to_string(dir.join("second.md.out")).unwrap(), "second 1\n");
+}
+
+#[test]
+fn in_place_suffix_writes_nothing_for_a_missing_input() {
+    let dir = temp_dir();
+    let missing = dir.join("nope.md");
+
+    let out = run_textpp(&["--in-place-suffix", ".out", missing.to_str().unwrap()]);
+
+    assert_eq!(out.status.code(), Some(12));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains(&format!("cannot read {}", missing.display())), "{stderr}");
+    assert!(!dir.join("nope.md.out").exists());
+}
+
+#[test]
+fn in_place_strips_template_suffix() {
+    let dir = temp_dir();
+    let input = dir.join("foo.md.in");
+    write_file(&input, "value $$VAL$$\n");
+
+    let out = run_textpp(&["-DVAL=2", "--in-place", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(fs::read_to_string(dir.join("foo.md")).unwrap(), "value 2\n");
+}
+
+#[test]
+fn in_place_requires_template_suffix() {
+    let dir = temp_dir();
+    let input = dir.join("foo.md");
+    write_file(&input, "value\n");
+
+    let out = run_textpp(&["--in-place", input.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    assert_eq!(fs::read_to_string(&input).unwrap(), "value\n");
+}
+
+#[test]
+fn comparing_two_undefined_variables_is_equal_unless_flagged() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#if A == B\nsame\n#else\ndifferent\n#endif\n");
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "same\n");
+
+    let out = run_textpp(&["--undefined-compare-error", input.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(10));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("input.md:1: invalid expression: comparing undefined variables 'A' and 'B'"),
+        "{stderr}"
+    );
+
+    let out = run_textpp(&["--undefined-compare-error", "-DA=x", input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "different\n");
+}
+
+#[test]
+fn trailing_backslash_continues_directive_lines() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#if A == \"1\" && \\\n    B == \"2\"\nboth\n#endif\ntext \\\n#if (\\\n\n#endif\n",
+    );
+
+    let out = run_textpp(&["-DA=1", "-DB=2", input.to_str().unwrap()]);
+
+    assert_eq!(out.status.code(), Some(10));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "both\ntext \\\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("input.md:6: invalid expression"), "{stderr}");
+}
+
+#[test]
+fn in_matches_any_listed_value() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#if MODE in (\"dev\", \"test\")\nnon-prod\n#else\nprod\n#endif\n");
+
+    let out = run_textpp(&["-DMODE=test", input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "non-prod\n");
+
+    let out = run_textpp(&["-DMODE=prod", input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "prod\n");
+}
+
+#[test]
+fn contains_word_matches_whole_words_from_env() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#if env(\"ENABLED_FEATURES\") contains_word \"search\"\nsearch on\n#else\nsearch off\n#endif\n",
+    );
+
+    let hit = run_textpp_with_env(
+        &[input.to_str().unwrap()],
+        &[("ENABLED_FEATURES", "auth search  billing")],
+    );
+    assert!(hit.status.success());
+    assert_eq!(String::from_utf8_lossy(&hit.stdout), "search on\n");
+
+    let substring = run_textpp_with_env(
+        &[input.to_str().unwrap()],
+        &[("ENABLED_FEATURES", "searching research")],
+    );
+    assert!(substring.status.success());
+    assert_eq!(String::from_utf8_lossy(&substring.stdout), "search off\n");
+
+    let empty = run_textpp_with_env(&[input.to_str().unwrap()], &[("ENABLED_FEATURES", "")]);
+    assert!(empty.status.success());
+    assert_eq!(String::from_utf8_lossy(&empty.stdout), "search off\n");
+}
+
+#[test]
+fn preserve_shebang_ignores_first_line_of_included_files() {
+    let dir = temp_dir();
+    let input = dir.join("input.sh");
+    write_file(&dir.join("part.sh"), "#!$$TOOL$$ in part\necho part\n");
+    write_file(&input, "#!/usr/bin/env $$TOOL$$\n#include \"part.sh\"\n");
+
+    let out = run_textpp(&["--preserve-shebang", "-DTOOL=sh", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "#!/usr/bin/env $$TOOL$$\n#!sh in part\necho part\n"
+    );
+}
+
+#[test]
+fn preserve_shebang_skips_substitution_on_first_line() {
+    let dir = temp_dir();
+    let input = dir.join("input.sh");
+    write_file(&input, "#!/usr/bin/env $$TOOL$$\necho $$TOOL$$\n");
+
+    let preserved = run_textpp(&["--preserve-shebang", "-DTOOL=sh", input.to_str().unwrap()]);
+    assert!(preserved.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&preserved.stdout),
+        "#!/usr/bin/env $$TOOL$$\necho sh\n"
+    );
+
+    let default = run_textpp(&["-DTOOL=sh", input.to_str().unwrap()]);
+    assert!(default.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&default.stdout),
+        "#!/usr/bin/env sh\necho sh\n"
+    );
+}
+
+#[test]
+fn define_include_stores_processed_snippet() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    let snippet = dir.join("snippet.md");
+    write_file(&snippet, "<b>$$NAME$$</b>\n");
+    write_file(
+        &input,
+        "#define-include BADGE \"snippet.md\"\nHello $$BADGE$$, welcome!\n",
+    );
+
+    let out = run_textpp(&["-DNAME=Alice", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "Hello <b>Alice</b>, welcome!\n"
+    );
+}
+
+#[test]
+fn warning_directive_reports_and_continues() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "before\n#warning check me\nafter\n");
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "before\nafter\n");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("input.md:2: warning: check me"));
+}
+
+#[test]
+fn quiet_suppresses_warnings_but_keeps_fail_on_warning() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "before\n#warning check me\nafter\n");
+
+    let quiet = run_textpp(&["-q", input.to_str().unwrap()]);
+    assert!(quiet.status.success());
+    assert!(quiet.stderr.is_empty());
+
+    let failing = run_textpp(&["--quiet", "--fail-on-warning", input.to_str().unwrap()]);
+    assert!(!failing.status.success());
+    assert!(failing.stderr.is_empty());
+    assert_eq!(String::from_utf8_lossy(&failing.stdout), "before\nafter\n");
+}
+
+#[test]
+fn error_if_empty_fails_when_every_branch_is_excluded() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    let other = dir.join("other.md");
+    write_file(&input, "#if TARGET == \"web\"\nweb docs\n#endif\n\n");
+    write_file(&other, "footer\n");
+
+    let plain = run_textpp(&["-DTARGET=cli", input.to_str().unwrap()]);
+    let empty = run_textpp(&["--error-if-empty", "-DTARGET=cli", input.to_str().unwrap()]);
+    let combined = run_textpp(&[
+        "--error-if-empty",
+        "-DTARGET=cli",
+        input.to_str().unwrap(),
+        other.to_str().unwrap(),
+    ]);
+
+    assert!(plain.status.success());
+    assert_eq!(empty.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&empty.stderr).contains("output is empty"));
+    assert!(combined.status.success());
+    assert_eq!(String::from_utf8_lossy(&combined.stdout), "\nfooter\n");
+}
+
+#[test]
+fn fail_on_warning_fails_on_undefined_variable_with_unchanged_output() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "hello [$$MISSING$$]\nbye\n");
+
+    let plain = run_textpp(&["--warn-undefined", input.to_str().unwrap()]);
+    let failing = run_textpp(&["--warn-undefined", "--fail-on-warning", input.to_str().unwrap()]);
+
+    assert!(plain.status.success());
+    assert_eq!(failing.status.code(), Some(1));
+    assert_eq!(failing.stdout, plain.stdout);
+    assert_eq!(String::from_utf8_lossy(&failing.stdout), "hello []\nbye\n");
+    let stderr = String::from_utf8_lossy(&failing.stderr);
+    assert!(stderr.contains("input.md:1: warning: undefined variable 'MISSING'"), "{stderr}");
+}
+
+#[test]
+fn whitespace_only_and_padded_false_values_are_falsy() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#if FLAG\non\n#else\noff\n#endif\n");
+
+    for (flag, expected) in [(" ", "off\n"), (" false ", "off\n"), (" No", "off\n"), (" yes ", "on\n")] {
+        let define = format!("-DFLAG={flag}");
+        let out = run_textpp(&[&define, input.to_str().unwrap()]);
+        assert!(out.status.success());
+        assert_eq!(String::from_utf8_lossy(&out.stdout), expected, "FLAG={flag:?}");
+    }
+}
+
+#[test]
+fn arithmetic_operators_compute_integers() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#if 3 + 4 == 7\nsum\n#endif\n#if BUILD * 2 >= 100\nbig\n#endif\n\
+         #eval X = 20 - BUILD / 10 * 3\nx=$$X$$\n",
+    );
+
+    let out = run_textpp(&["-DBUILD=50", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "sum\nbig\nx=5\n");
+}
+
+#[test]
+fn arithmetic_supports_grouping_and_negation() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#if (A + 1) * 2 > 4\ngrouped\n#endif\n#if A > -1\nnon-negative\n#endif\n\
+         #eval X = -(A + 1) * 2\nx=$$X$$\n",
+    );
+
+    let out = run_textpp(&["-DA=2", input.to_str().unwrap()]);
+
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "grouped\nnon-negative\nx=-6\n");
+}
+
+#[test]
+fn arithmetic_rejects_division_by_zero_and_non_numbers() {
+    let dir = temp_dir();
+    let zero = dir.join("zero.md");
+    let word = dir.join("word.md");
+    write_file(&zero, "#if 10 / 0\n#endif\n");
+    write_file(&word, "#if NAME * 2 > 1\n#endif\n");
+
+    let zero_out = run_textpp(&[zero.to_str().unwrap()]);
+    let word_out = run_textpp(&["-DNAME=bob", word.to_str().unwrap()]);
+
+    assert!(!zero_out.status.success());
+    let stderr = String::from_utf8_lossy(&zero_out.stderr);
+    assert!(stderr.contains("invalid expression: division by zero"), "{stderr}");
+    assert!(!word_out.status.success());
+    let stderr = String::from_utf8_lossy(&word_out.stderr);
+    assert!(stderr.contains("'*' expects a number, got 'bob'"), "{stderr}");
+}
+
+#[test]
+fn tilde_equals_compares_ignoring_case() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#if MODE ~= \"release\"\nloose\n#endif\n#if MODE == \"release\"\nstrict\n#endif\n",
+    );
+
+    let out = run_textpp(&["-DMODE=Release", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "loose\n");
+}
+
+#[test]
+fn hex_and_binary_literals_compare_as_decimal() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#if 0x10 == 16\nhex\n#endif\n#if FLAGS == 0xFF && 0b1010 + 0X1 == 11\nflags\n#endif\n",
+    );
+
+    let out = run_textpp(&["-DFLAGS=255", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "hex\nflags\n");
+}
+
+#[test]
+fn hex_define_values_are_numbers() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#if FLAGS == 0xFF\nequal\n#endif\n#if FLAGS == 255\ndecimal\n#endif\n\
+         #if FLAGS > 0x0F\ngreater\n#endif\n#eval NEXT = FLAGS + 1\nnext=$$NEXT$$\n",
+    );
+
+    let out = run_textpp(&["-DFLAGS=0xFF", input.to_str().unwrap()]);
+
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "equal\ndecimal\ngreater\nnext=256\n"
+    );
+}
+
+#[test]
+fn malformed_hex_and_binary_literals_fail() {
+    let dir = temp_dir();
+    for expr in ["0x", "0b102", "0xZZ"] {
+        let input = dir.join("input.md");
+        write_file(&input, &format!("#if {expr} == 1\n#endif\n"));
+
+        let out = run_textpp(&[input.to_str().unwrap()]);
+
+        assert!(!out.status.success(), "{expr}");
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("invalid expression: malformed number"), "{expr}: {stderr}");
+    }
+}
+
+#[test]
+fn between_checks_inclusive_numeric_range() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#if AGE between 18 and 65\nin\n#else\nout\n#endif\n");
+
+    for (age, expected) in [
+        ("30", "in\n"),
+        ("17", "out\n"),
+        ("66", "out\n"),
+        ("18", "in\n"),
+        ("65", "in\n"),
+    ] {
+        let define = format!("-DAGE={age}");
+        let out = run_textpp(&[&define, input.to_str().unwrap()]);
+        assert!(out.status.success());
+        assert_eq!(String::from_utf8_lossy(&out.stdout), expected, "AGE={age}");
+    }
+}
+
+#[test]
+fn ternary_selects_value_by_condition() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#if (MODE == \"x\" ? \"1\" : \"0\") == \"1\"\nx\n#else\nother\n#endif\n\
+         #eval SUFFIX = (MODE == \"x\" ? \"-x\" : \"-\" + MODE)\nname$$SUFFIX$$\n",
+    );
+
+    let x = run_textpp(&["-DMODE=x", input.to_str().unwrap()]);
+    let y = run_textpp(&["-DMODE=y", input.to_str().unwrap()]);
+
+    assert!(x.status.success());
+    assert_eq!(String::from_utf8_lossy(&x.stdout), "x\nname-x\n");
+    assert!(y.status.success());
+    assert_eq!(String::from_utf8_lossy(&y.stdout), "other\nname-y\n");
+}
+
+#[test]
+fn ternary_binds_loosest_at_top_level() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#if MODE == \"x\" ? FLAG : \"yes\"\nshown\n#else\nhidden\n#endif\n");
+
+    for (mode, flag, expected) in [
+        ("x", "1", "shown\n"),
+        ("x", "0", "hidden\n"),
+        ("y", "0", "shown\n"),
+    ] {
+        let mode = format!("-DMODE={mode}");
+        let flag = format!("-DFLAG={flag}");
+        let out = run_textpp(&[&mode, &flag, input.to_str().unwrap()]);
+        assert!(out.status.success());
+        assert_eq!(String::from_utf8_lossy(&out.stdout), expected, "{mode} {flag}");
+    }
+}
+
+#[test]
+fn between_rejects_non_numeric_operands() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#if AGE between 18 and 65\nin\n#endif\n");
+
+    let out = run_textpp(&["-DAGE=old", input.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("invalid expression"));
+}
+
+#[test]
+fn roundtrippable_output_rehydrates_to_original_directives() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    let include = dir.join("inc.md");
+    let emitted = dir.join("emitted.md");
+    let source = "intro\n#ifdef A\nyes\n#else\nno -- 100%\n#endif\n#include \"inc.md\"\n#define X 1\nend\n";
+    write_file(&include, "included\n#ifdef A\ninner\n#endif\n");
+    write_file(&input, source);
+
+    let out = run_textpp(&["--roundtrippable", "-DA=1", input.to_str().unwrap()]);
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("<!-- textpp: #ifdef A -->\nyes\n"));
+    assert!(stdout.contains("<!-- textpp-skip: no %2D%2D 100%25 -->"));
+    assert!(stdout.contains("included\n"));
+    write_file(&emitted, &stdout);
+
+    let back = run_textpp(&["--rehydrate", emitted.to_str().unwrap()]);
+    assert!(back.status.success());
+    assert_eq!(String::from_utf8_lossy(&back.stdout), source);
+}
+
+#[test]
+fn decode_escapes_turns_backslash_n_into_newline() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "$$FOOTER$$\n");
+
+    let out = run_textpp(&["--decode-escapes", r"-DFOOTER=line1\nline2", input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "line1\nline2\n");
+
+    let out = run_textpp(&[r"-DFOOTER=line1\nline2", input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "line1\\nline2\n");
+}
+
+#[test]
+fn textpp_defines_env_applies_before_cli_defines() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "$$EDITION$$ $$TIER$$\n#ifdef DRAFT\ndraft\n#endif\n");
+    let vars = [("TEXTPP_DEFINES", "EDITION=pro;TIER=gold;;DRAFT;bad key")];
+
+    let out = run_textpp_with_env(&[input.to_str().unwrap()], &vars);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "pro gold\ndraft\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("warning: ignoring empty TEXTPP_DEFINES entry"), "{stderr}");
+    assert!(stderr.contains("invalid define 'bad key'"), "{stderr}");
+
+    let out = run_textpp_with_env(&["-DTIER=silver", input.to_str().unwrap()], &vars);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "pro silver\ndraft\n");
+}
+
+#[test]
+fn undef_flag_cancels_defines() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#ifdef KEY\nkey\n#else\nno key\n#endif\n");
+
+    let out = run_textpp(&["-DKEY=1", "-UKEY", input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "no key\n");
+
+    let out = run_textpp(&["--undef", "KEY", "-DKEY=1", input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "no key\n");
+}
+
+#[test]
+fn version_and_help_exit_successfully() {
+    let out = run_textpp(&["--version"]);
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert_eq!(stdout, format!("textpp {}\n", env!("CARGO_PKG_VERSION")));
+
+    let out = run_textpp(&["-V"]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), stdout);
+
+    let out = run_textpp(&["--help"]);
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).starts_with("usage: textpp"));
+}
+
+#[test]
+fn response_file_arguments_are_spliced_in() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "$$A$$ $$B$$ [$$C$$]\n");
+    let nested = dir.join("nested.rsp");
+    write_file(&nested, "-DC=\"two words\"\n");
+    let rsp = dir.join("args.rsp");
+    write_file(&rsp, &format!("-DA=1\n-DB=2 @{}\n", nested.display()));
+    let arg = format!("@{}", rsp.display());
+
+    let out = run_textpp(&[&arg, input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "1 2 [two words]\n");
+
+    write_file(&nested, &format!("@{}\n", rsp.display()));
+    let out = run_textpp(&[&arg, input.to_str().unwrap()]);
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("response file cycle detected"), "{stderr}");
+}
+
+#[test]
+fn define_file_sets_value_to_file_contents() {
+    let dir = temp_dir();
+    let header = dir.join("header.txt");
+    write_file(&header, "line one\nline two\n");
+    let input = dir.join("input.md");
+    write_file(&input, "before $$HEADER$$ after\n");
+    let spec = format!("HEADER={}", header.display());
+
+    let out = run_textpp(&["--define-file", &spec, input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "before line one\nline two after\n");
+
+    let missing = format!("HEADER={}", dir.join("missing.txt").display());
+    let out = run_textpp(&["--define-file", &missing, input.to_str().unwrap()]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("cannot read define file"));
+
+    let out = run_textpp(&["--keep-going", "--define-file", &missing, input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "before  after\n");
+}
+
+#[test]
+fn defines_stdin_seeds_defines_and_cli_overrides() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "$$NAME$$ $$TITLE$$ $$ROLE$$ $$FLAG$$\n");
+
+    let out = run_textpp_with_stdin(
+        &["--defines-stdin", "-DROLE=admin", input.to_str().unwrap()],
+        "# generated\nNAME=Alice\nTITLE = \"Dr.\"\nROLE=user\n\nFLAG\n",
+    );
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "Alice Dr. admin TRUE\n");
+}
+
+#[test]
+fn defines_stdin_keeps_empty_values_defined_and_skips_comments() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#ifdef EMPTY\nempty defined\n#endif\n#ifdef SKIPPED\nskipped\n#endif\n[$$EMPTY$$]\n",
+    );
+
+    let out = run_textpp_with_stdin(
+        &["--defines-stdin", input.to_str().unwrap()],
+        "# SKIPPED=1\n\nEMPTY=\n",
+    );
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "empty defined\n[]\n");
+}
+
+#[test]
+fn for_repeats_body_for_each_item() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#for I in $$ITEMS$$ z\n- $$I$$\n#endfor\nafter: [$$I$$]\n");
+
+    let out = run_textpp(&["-DITEMS=x y", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "- x\n- y\n- z\nafter: []\n");
+}
+
+#[test]
+fn break_ends_loop_early() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#for I in a b c d\n#if I == STOP\n#break\n#endif\n- $$I$$\n#endfor\ndone\n",
+    );
+
+    let out = run_textpp(&["-DSTOP=c", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "- a\n- b\ndone\n");
+}
+
+#[test]
+fn continue_skips_one_item() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#for I in a b c\n#if I == \"b\"\n#continue\n#endif\n- $$I$$\n#endfor\n",
+    );
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "- a\n- c\n");
+}
+
+#[test]
+fn break_outside_loop_fails() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "a\n#break\nb\n");
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("#break outside of #for"));
+}
+
+#[test]
+fn import_env_regex_imports_only_matching_vars() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "[$$APP_PORT$$] [$$APP_NAME$$] [$$XAPP_MODE$$] [$$OTHER$$]\n",
+    );
+
+    let out = run_textpp_with_env(
+        &["--import-env-regex", "^APP_[A-Z]+$", input.to_str().unwrap()],
+        &[
+            ("APP_PORT", "8080"),
+            ("APP_NAME", "demo"),
+            ("XAPP_MODE", "dev"),
+            ("OTHER", "nope"),
+        ],
+    );
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "[8080] [demo] [] []\n");
+}
+
+#[test]
+fn import_env_regex_strips_prefix() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "[$$PORT$$] [$$APP_PORT$$] [$$HOST$$]\n");
+
+    let out = run_textpp_with_env(
+        &[
+            "--import-env-regex",
+            "^APP_",
+            "--strip-env-prefix",
+            "APP_",
+            "-DHOST=cli",
+            input.to_str().unwrap(),
+        ],
+        &[("APP_PORT", "8080"), ("APP_HOST", "env")],
+    );
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "[8080] [] [cli]\n");
+}
+
+#[test]
+fn import_env_regex_rejects_unsupported_syntax() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "text\n");
+
+    let out = run_textpp(&["--import-env-regex", "^(APP|WEB)_", input.to_str().unwrap()]);
+
+    assert_eq!(out.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&out.stderr)
+        .contains("invalid --import-env-regex '^(APP|WEB)_': unsupported '('"));
+}
+
+#[test]
+fn print_hash_is_stable_and_tracks_defines() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "abc$$SUFFIX$$\n");
+
+    let first = run_textpp(&["--print-hash", input.to_str().unwrap()]);
+    let second = run_textpp(&["--print-hash", input.to_str().unwrap()]);
+    let changed = run_textpp(&["--print-hash", "-DSUFFIX=x", input.to_str().unwrap()]);
+
+    assert!(first.status.success());
+    assert_eq!(String::from_utf8_lossy(&first.stdout), "abc\n");
+    // SHA-256 of "abc\n".
+    assert_eq!(
+        String::from_utf8_lossy(&first.stderr).trim(),
+        "edeaaff3f1774ad2888673770c6d64097e391bc362d7d6fb34982ddf0efd18cb"
+    );
+    assert_eq!(first.stderr, second.stderr);
+    assert_ne!(first.stderr, changed.stderr);
+}
+
+#[test]
+fn include_align_pads_lines_to_column() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    let include = dir.join("col.txt");
+    write_file(&include, "first $$VAL$$\n\nsecond\n");
+    write_file(&input, "header\n#include \"col.txt\" align 8\nfooter\n");
+
+    let out = run_textpp(&["-DVAL=1", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "header\n        first 1\n\n        second\nfooter\n"
+    );
+}
+
+#[test]
+fn define_supports_bare_equals_and_empty_forms() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#define A\n#define B=x y\n#define C=\n#define D \"\"\n[$$A$$][$$B$$][$$C$$][$$D$$]\n#ifdef C\nC defined\n#endif\n#ifdef D\nD defined\n#endif\n",
+    );
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "[TRUE][x y][][]\nC defined\nD defined\n"
+    );
+}
+
+#[test]
+fn define_in_inactive_branch_is_ignored() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#ifdef NOPE\n#define KEY inside\n#endif\nvalue: [$$KEY$$]\n",
+    );
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "value: []\n");
+}
+
+#[test]
+fn undef_flips_ifdef_and_ignores_unknown_keys() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#ifdef FEATURE\nbefore\n#endif\n#undef FEATURE\n#undef NEVER_DEFINED\n#ifdef FEATURE\nafter\n#else\ngone\n#endif\n",
+    );
+
+    let out = run_textpp(&["-DFEATURE", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "before\ngone\n");
+}
+
+#[test]
+fn define_count_and_any_defined() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#if define_count() > 0\ncustom defines\n#endif\n#if any_defined(\"A\", \"B\", \"C\")\nsome of A B C\n#endif\n#if any_defined(\"X\", \"Y\")\nsome of X Y\n#endif\n#eval COUNT = define_count()\ncount=$$COUNT$$\n",
+    );
+
+    let out = run_textpp(&["-DB=1", "-DX=", input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "custom defines\nsome of A B C\ncount=1\n"
+    );
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "count=0\n");
+}
+
+#[test]
+fn any_defined_tests_names_not_values() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#if any_defined(A)\nA bare\n#endif\n#if any_defined(\"A\")\nA quoted\n#endif\n#if any_defined(B, C)\nB or C\n#endif\n",
+    );
+
+    let out = run_textpp(&["-DA=B", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "A bare\nA quoted\n");
+}
+
+#[test]
+fn elif_chain_takes_first_matching_branch() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#if LEVEL == 1\none\n#elif LEVEL == 2\ntwo\n#elifdef SHOUT\nloud\n#elifndef QUIET\nnormal\n#else\nquiet\n#endif\n",
+    );
+    let render = |args: &[&str]| {
+        let mut args = args.to_vec();
+        args.push(input.to_str().unwrap());
+        let out = run_textpp(&args);
+        assert!(out.status.success());
+        String::from_utf8_lossy(&out.stdout).into_owned()
+    };
+
+    assert_eq!(render(&["-DLEVEL=1", "-DSHOUT"]), "one\n");
+    assert_eq!(render(&["-DLEVEL=2", "-DSHOUT"]), "two\n");
+    assert_eq!(render(&["-DSHOUT"]), "loud\n");
+    assert_eq!(render(&[]), "normal\n");
+    assert_eq!(render(&["-DQUIET"]), "quiet\n");
+}
+
+#[test]
+fn elif_inside_inactive_branch_stays_inactive() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#ifdef OUTER\n#if 0\na\n#elif 1\nb\n#endif\n#else\nc\n#endif\n",
+    );
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "c\n");
+}
+
+#[test]
+fn elif_after_else_is_error() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#ifdef A\na\n#else\nb\n#elif 1\nc\n#endif\n");
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("invalid directive structure: #elif after #else"), "{stderr}");
+}
+
+#[test]
+fn input_glob_writes_each_match_to_out_dir() {
+    let dir = temp_dir();
+    write_file(&dir.join("src/a.md.tmpl"), "A $$V$$\n");
+    write_file(&dir.join("src/b.md.tmpl"), "B $$V$$\n");
+    write_file(&dir.join("src/notes.txt"), "not a template\n");
+    let pattern = dir.join("src/*.tmpl");
+    let build = dir.join("build");
+
+    let out = run_textpp(&[
+        "-DV=1",
+        "--input-glob",
+        pattern.to_str().unwrap(),
+        "--out-dir",
+        build.to_str().unwrap(),
+        "--in-place",
+    ]);
+
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(fs::read_to_string(build.join("a.md")).unwrap(), "A 1\n");
+    assert_eq!(fs::read_to_string(build.join("b.md")).unwrap(), "B 1\n");
+    assert_eq!(fs::read_dir(&build).unwrap().count(), 2);
+}
+
+#[test]
+fn continue_on_error_processes_remaining_inputs() {
+    let dir = temp_dir();
+    write_file(&dir.join("src/a.tmpl"), "#ifdef X\nunterminated\n");
+    write_file(&dir.join("src/b.tmpl"), "fine\n");
+    let pattern = dir.join("src/*.tmpl");
+    let build = dir.join("build");
+    let args = [
+        "--input-glob",
+        pattern.to_str().unwrap(),
+        "--out-dir",
+        build.to_str().unwrap(),
+    ];
+
+    let out = run_textpp(&args);
+    assert!(!out.status.success());
+    assert!(!build.join("b.tmpl").exists());
+
+    let mut with_continue = args.to_vec();
+    with_continue.push("--continue-on-error");
+    let out = run_textpp(&with_continue);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("missing #endif"));
+    assert_eq!(fs::read_to_string(build.join("b.tmpl")).unwrap(), "fine\n");
+}
+
+#[test]
+fn input_glob_without_matches_is_error() {
+    let dir = temp_dir();
+    let pattern = dir.join("*.nothing");
+
+    let out = run_textpp(&["--input-glob", pattern.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("no files match"));
+}
+
+#[test]
+fn substitution_preserves_multibyte_text() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "héllo $$NAME$$ 日本語 🎉\n$$NAME$$é\n");
+
+    let out = run_textpp(&["-DNAME=wörld", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "héllo wörld 日本語 🎉\nwörldé\n"
+    );
+}
+
+#[test]
+fn include_path_with_multibyte_characters() {
+    let dir = temp_dir();
+    write_file(&dir.join("résumé-en.md"), "included\n");
+    let input = dir.join("input.md");
+    write_file(&input, "#include \"résumé-##LANG##.md\"\n");
+
+    let out = run_textpp(&["-DLANG=en", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "included\n");
+}
+
+#[test]
+fn split_defines_variables_from_parts() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#split $$PAIR$$ on \"=\" into K V\n$$K$$ -> $$V$$\n#split \"x;y;z\" on \";\" into FIRST REST\n$$FIRST$$ | $$REST$$\n#split solo on \",\" into A B\n[$$A$$]\n#ifndef B\nB undefined\n#endif\n",
+    );
+
+    let out = run_textpp(&["-DPAIR=a=b", input.to_str().unwrap()]);
+
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "a -> b\nx | y;z\n[solo]\nB undefined\n"
+    );
+}
+
+#[test]
+fn include_dirs_are_canonicalized_and_deduplicated() {
+    let dir = temp_dir();
+    write_file(&dir.join("lib1/a.md"), "a from lib1\n");
+    write_file(&dir.join("lib2/a.md"), "a from lib2\n");
+    write_file(&dir.join("lib2/b.md"), "b from lib2\n");
+    let input = dir.join("doc/input.md");
+    write_file(&input, "#include \"a.md\"\n#include \"b.md\"\n");
+    let lib1_via_lib2 = dir.join("lib2/../lib1");
+    let lib1 = dir.join("lib1");
+    let lib2 = dir.join("lib2/");
+    let missing = dir.join("missing");
+
+    let out = run_textpp(&[
+        "-I",
+        lib1_via_lib2.to_str().unwrap(),
+        "-I",
+        lib1.to_str().unwrap(),
+        &format!("-I{}", lib2.display()),
+        "-I",
+        lib2.to_str().unwrap(),
+        "-I",
+        missing.to_str().unwrap(),
+        "--warn-missing-include-dirs",
+        input.to_str().unwrap(),
+    ]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "a from lib1\nb from lib2\n"
+    );
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert_eq!(stderr.matches("warning").count(), 1, "{stderr}");
+    assert!(stderr.contains("missing"), "{stderr}");
+}
+
+#[test]
+fn errors_report_file_and_line() {
+    let dir = temp_dir();
+    write_file(&dir.join("part.md"), "fine\n#if A &\nx\n#endif\n");
+    let input = dir.join("input.md");
+    write_file(&input, "top\n#include \"part.md\"\n");
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let expected = format!("{}:2: invalid expression", dir.join("part.md").display());
+    assert!(stderr.contains(&expected), "{stderr}");
+}
+
+#[test]
+fn missing_endif_reports_opening_line() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "a\n#ifdef X\n#endif\n#ifdef Y\nb\n");
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let expected = format!("{}:4: invalid directive structure: missing #endif", input.display());
+    assert!(stderr.contains(&expected), "{stderr}");
+}
+
+#[test]
+fn include_cycle_is_error() {
+    let dir = temp_dir();
+    write_file(&dir.join("a.md"), "a\n#include \"b.md\"\n");
+    write_file(&dir.join("b.md"), "b\n#include \"a.md\"\n");
+    write_file(&dir.join("self.md"), "#include \"self.md\"\n");
+
+    let out = run_textpp(&[dir.join("a.md").to_str().unwrap()]);
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("include cycle detected"), "{stderr}");
+    assert!(stderr.contains("b.md:2:"), "{stderr}");
+
+    let out = run_textpp(&[dir.join("self.md").to_str().unwrap()]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("include cycle detected"));
+}
+
+#[test]
+fn emit_tree_json_describes_includes_and_branches() {
+    let dir = temp_dir();
+    write_file(&dir.join("inner.md"), "#ifdef X\nx\n#else\nno x\n#endif\n");
+    let input = dir.join("input.md");
+    write_file(&input, "#ifdef X\n#include \"inner.md\"\n#endif\n");
+    let tree = dir.join("tree.json");
+
+    let out = run_textpp(&[
+        "-DX",
+        "--emit-tree-json",
+        tree.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "x\n");
+    let expected = format!(
+        "{{\"file\":\"{}\",\"children\":[{{\"line\":1,\"directive\":\"#ifdef X\",\"taken\":true}},{{\"file\":\"{}\",\"children\":[{{\"line\":1,\"directive\":\"#ifdef X\",\"taken\":true}},{{\"line\":3,\"directive\":\"#else\",\"taken\":false}}]}}]}}\n",
+        input.display(),
+        dir.join("inner.md").display()
+    );
+    assert_eq!(fs::read_to_string(&tree).unwrap(), expected);
+}
+
+#[test]
+fn emit_tree_json_to_stdout_replaces_document() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#if 0\nhidden\n#endif\nshown\n");
+
+    let out = run_textpp(&["--emit-tree-json", "-", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(!stdout.contains("shown"), "{stdout}");
+    assert!(stdout.contains("{\"line\":1,\"directive\":\"#if 0\",\"taken\":false}"), "{stdout}");
+}
+
+#[test]
+fn emit_ast_describes_nested_blocks_without_processing() {
+    let dir = temp_dir();
+    let input = dir.join("ast.md");
+    write_file(
+        &input,
+        "#if MODE == \"a\"\n#ifdef X\n#include \"x.md\"\n#endif\n#elif 1\ntext\n#endif\n",
+    );
+
+    let out = run_textpp(&["--emit-ast", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let inner = "{\"type\":\"conditional\",\"line\":2,\"end\":4,\"branches\":[{\"directive\":\"#ifdef X\",\"line\":2,\"end\":3,\"children\":[{\"type\":\"include\",\"line\":3,\"path\":\"x.md\"}]}]}";
+    let expected = format!(
+        "\"children\":[{{\"type\":\"conditional\",\"line\":1,\"end\":7,\"branches\":[{{\"directive\":\"#if MODE == \\\"a\\\"\",\"line\":1,\"end\":4,\"children\":[{inner}]}},{{\"directive\":\"#elif 1\",\"line\":5,\"end\":6,\"children\":[]}}]}}]}}\n"
+    );
+    assert!(stdout.ends_with(&expected), "{stdout}");
+
+    write_file(&input, "#ifdef X\n");
+    let out = run_textpp(&["--emit-ast", input.to_str().unwrap()]);
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("ast.md:1: invalid directive structure: missing #endif"), "{stderr}");
+}
+
+#[test]
+fn quoted_include_path_keeps_spaces() {
+    let dir = temp_dir();
+    write_file(&dir.join("inc/my file.txt"), "spaced $$X$$\n");
+    write_file(&dir.join("inc/plain.txt"), "plain\n");
+    let input = dir.join("input.md");
+    write_file(&input, "#include \"inc/my file.txt\"\n#include inc/plain.txt trailing\n");
+
+    let out = run_textpp(&["-DX=ok", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "spaced ok\nplain\n");
+}
+
+#[test]
+fn max_include_depth_limits_nesting() {
+    let dir = temp_dir();
+    write_file(&dir.join("one.md"), "one\n#include \"two.md\"\n");
+    write_file(&dir.join("two.md"), "two\n#include \"three.md\"\n");
+    write_file(&dir.join("three.md"), "three\n");
+    let input = dir.join("input.md");
+    write_file(&input, "#include \"one.md\"\n");
+
+    let out = run_textpp(&["--max-include-depth", "3", input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "one\ntwo\nthree\n");
+
+    let out = run_textpp(&["--max-include-depth", "2", input.to_str().unwrap()]);
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("include depth limit (2) exceeded by"), "{stderr}");
+    assert!(stderr.contains("three.md"), "{stderr}");
+}
+
+#[test]
+fn max_output_bytes_stops_runaway_output() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#for N in 1 2 3 4 5 6 7 8 9\nline $$N$$\n#endfor\n");
+
+    let out = run_textpp(&["--max-output-bytes", "14", input.to_str().unwrap()]);
+    assert!(!out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "line 1\nline 2\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("output size limit exceeded"), "{stderr}");
+
+    let out = run_textpp(&["--max-output-bytes", "63", input.to_str().unwrap()]);
+    assert!(out.status.success());
+}
+
+#[test]
+fn max_output_bytes_counts_all_inputs_together() {
+    let dir = temp_dir();
+    let a = dir.join("a.md");
+    let b = dir.join("b.md");
+    write_file(&a, "aaaaa\n");
+    write_file(&b, "bbbbb\n");
+
+    let out = run_textpp(&["--max-output-bytes", "8", a.to_str().unwrap(), b.to_str().unwrap()]);
+    let batch = run_textpp(&[
+        "--max-output-bytes",
+        "8",
+        "--in-place-suffix",
+        ".out",
+        a.to_str().unwrap(),
+        b.to_str().unwrap(),
+    ]);
+    let fits = run_textpp(&["--max-output-bytes", "12", a.to_str().unwrap(), b.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "aaaaa\n");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("output size limit exceeded"));
+    assert!(!batch.status.success());
+    assert!(!dir.join("b.md.out").exists());
+    assert!(fits.status.success());
+    assert_eq!(String::from_utf8_lossy(&fits.stdout), "aaaaa\nbbbbb\n");
+}
+
+#[test]
+fn warn_empty_includes_flags_empty_files_only() {
+    let dir = temp_dir();
+    write_file(&dir.join("empty.md"), "");
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "a\n#include \"empty.md\"\n#include \"missing.md\"\nb\n",
+    );
+
+    let out = run_textpp(&["--warn-empty-includes", input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "a\nb\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let expected = format!("{}:2: warning: included file", input.display());
+    assert!(stderr.contains(&expected), "{stderr}");
+    assert!(stderr.contains("empty.md' is empty"), "{stderr}");
+    assert!(!stderr.contains("missing.md"), "{stderr}");
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert!(out.stderr.is_empty());
+}
+
+#[test]
+fn strict_includes_rejects_missing_include() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "before\n#include \"inc/part.txt\"\nafter\n");
+
+    let out = run_textpp(&["--strict-includes", input.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    // Output is streamed, so lines before the error have already been written.
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "before\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let expected = format!(
+        "{}:2: cannot read include: {}",
+        input.display(),
+        dir.join("inc/part.txt").display()
+    );
+    assert!(stderr.contains(&expected), "{stderr}");
+}
+
+#[test]
+fn no_newline_on_include_splices_include_without_trailing_newline() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&dir.join("name.txt"), "Hello");
+    write_file(&dir.join("outer.txt"), "#include \"name.txt\"");
+    write_file(&dir.join("raw.txt"), "raw");
+    write_file(
+        &input,
+        "#include \"name.txt\"\n, world\n#include \"outer.txt\"\n!\n#include_raw \"raw.txt\"\n?\n",
+    );
+
+    let default = run_textpp(&[input.to_str().unwrap()]);
+    let spliced = run_textpp(&["--no-newline-on-include", input.to_str().unwrap()]);
+
+    assert!(default.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&default.stdout),
+        "Hello\n, world\nHello\n!\nraw\n?\n"
+    );
+    assert!(spliced.status.success());
+    assert_eq!(String::from_utf8_lossy(&spliced.stdout), "Hello, world\nHello!\nraw?\n");
+}
+
+#[test]
+fn include_raw_inserts_file_verbatim() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&dir.join("sample-v2.txt"), "#if X\nprice $$X$$\n#endif");
+    write_file(
+        &input,
+        "before $$X$$\n#include_raw \"sample-##V##.txt\"\n#include_raw \"missing.txt\"\nafter\n",
+    );
+
+    let out = run_textpp(&["-DX=1", "-DV=v2", input.to_str().unwrap()]);
+    let strict = run_textpp(&["--strict-includes", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "before 1\n#if X\nprice $$X$$\n#endif\nafter\n"
+    );
+    assert!(!strict.status.success());
+    assert!(String::from_utf8_lossy(&strict.stderr).contains("cannot read include"));
+}
+
+#[test]
+fn keep_going_reports_every_missing_include() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&dir.join("ok.md"), "ok\n");
+    write_file(
+        &input,
+        "before\n#include \"one.md\"\n#include \"ok.md\"\n#include \"two.md\"\nafter\n",
+    );
+
+    let out = run_textpp(&["--strict-includes", "--keep-going", input.to_str().unwrap()]);
+
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "before\nok\nafter\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    for (line, name) in [(2, "one.md"), (4, "two.md")] {
+        let expected = format!(
+            "{}:{line}: cannot read include: {}",
+            input.display(),
+            dir.join(name).display()
+        );
+        assert!(stderr.contains(&expected), "{stderr}");
+    }
+}
+
+#[test]
+fn pragma_undefined_changes_policy_mid_file() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "early: [$$NOPE$$]\n#pragma undefined=warn\nwarned: [$$NOPE$$]\n#pragma undefined=default\nquiet: [$$NOPE$$]\n#pragma undefined=error\nlate: [$$NOPE$$]\n",
+    );
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let warning = format!("{}:3: warning: undefined variable 'NOPE'", input.display());
+    let error = format!("{}:7: undefined variable 'NOPE'", input.display());
+    assert!(stderr.contains(&warning), "{stderr}");
+    assert!(stderr.contains(&error), "{stderr}");
+    assert_eq!(stderr.matches("undefined variable").count(), 2, "{stderr}");
+}
+
+#[test]
+fn pragma_undefined_ends_with_file() {
+    let dir = temp_dir();
+    write_file(&dir.join("part.md"), "#pragma undefined=error\npart\n");
+    let input = dir.join("input.md");
+    write_file(&input, "#include \"part.md\"\nafter: [$$NOPE$$]\n");
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "part\nafter: []\n");
+}
+
+#[test]
+fn foreign_pragmas_pass_through_as_text() {
+    let dir = temp_dir();
+    let input = dir.join("input.c");
+    write_file(
+        &input,
+        "#pragma warning(disable: 4996)\n#pragma GCC optimize(\"O$$LEVEL$$\")\n#pragma once\nint x;\n",
+    );
+
+    let out = run_textpp(&["-DLEVEL=2", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "#pragma warning(disable: 4996)\n#pragma GCC optimize(\"O2\")\nint x;\n"
+    );
+}
+
+#[test]
+fn output_flag_writes_file_only_on_success() {
+    let dir = temp_dir();
+    let good = dir.join("good.md");
+    let bad = dir.join("bad.md");
+    let target = dir.join("out.txt");
+    write_file(&good, "hello $$NAME$$\n");
+    write_file(&bad, "#ifdef NAME\nunterminated\n");
+
+    let out = run_textpp(&[
+        "-DNAME=Alice",
+        "--output",
+        target.to_str().unwrap(),
+        good.to_str().unwrap(),
+    ]);
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+    assert_eq!(fs::read_to_string(&target).unwrap(), "hello Alice\n");
+
+    let out = run_textpp(&["-o", target.to_str().unwrap(), bad.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(11));
+    assert_eq!(fs::read_to_string(&target).unwrap(), "hello Alice\n");
+}
+
+#[test]
+fn dash_reads_input_from_stdin() {
+    let out = run_textpp_with_stdin(&["-DVAR=piped", "-"], "value: $$VAR$$\n#ifdef VAR\nyes\n#endif\n");
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "value: piped\nyes\n");
+}
+
+#[test]
+fn check_validates_without_output() {
+    let dir = temp_dir();
+    write_file(&dir.join("check/part.md"), "#if 1 == 1\npart\n");
+    let broken = dir.join("check/broken.md");
+    write_file(&broken, "text\n#include \"part.md\"\n");
+    let good = dir.join("check/good.md");
+    write_file(&good, "#ifdef X\nx\n#else\ntext\n#endif\n");
+
+    let out = run_textpp(&["--check", broken.to_str().unwrap()]);
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("part.md:1: invalid directive structure: missing #endif"), "{stderr}");
+
+    let out = run_textpp(&["--check", good.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+}
+
+#[test]
+fn check_fails_for_a_missing_input() {
+    let dir = temp_dir();
+    let missing = dir.join("nonexistent.md");
+
+    let out = run_textpp(&["--check", missing.to_str().unwrap()]);
+
+    assert_eq!(out.status.code(), Some(12));
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains(&format!("cannot read {}", missing.display())), "{stderr}");
+}
+
+#[test]
+fn stdin_input_errors_name_stdin() {
+    let out = run_textpp_with_stdin(&["-"], "ok\n#ifdef X\n");
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("<stdin>:2: invalid directive structure: missing #endif"), "{stderr}");
+}
+
+#[test]
+fn stdin_name_labels_stdin_errors() {
+    let out = run_textpp_with_stdin(&["--stdin-name", "template.md", "-"], "ok\n#if 1 ==\n#endif\n");
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("template.md:2: invalid expression"), "{stderr}");
+    assert!(!stderr.contains("<stdin>"), "{stderr}");
+}
+
+#[test]
+fn relational_operators_compare_numbers() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#if 5 > 10\nfive above ten\n#endif\n#if VERSION >= 3\nv3+\n#endif\n#if VERSION < 3\nold\n#endif\n#if 10 <= 10 && 2 < 10\nle lt\n#endif\n",
+    );
+
+    let out = run_textpp(&["-DVERSION=3", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "v3+\nle lt\n");
+}
+
+#[test]
+fn relational_operator_rejects_non_numbers() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#if VERSION > 3\nx\n#endif\n");
+
+    let out = run_textpp(&["-DVERSION=beta", input.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("invalid expression: relational operator requires numbers"), "{stderr}");
+}
+
+#[test]
+fn defined_function_ignores_value() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#define A=\n#if defined(A)\nA defined\n#endif\n#if A\nA truthy\n#endif\n#if defined(B) && B == 2\nB is 2\n#endif\n#if defined(C) || !defined(\"C\")\nC undefined\n#endif\n",
+    );
+
+    let out = run_textpp(&["-DB=2", input.to_str().unwrap()]);
+
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "A defined\nB is 2\nC undefined\n"
+    );
+}
+
+#[test]
+fn and_or_short_circuit_guards() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#if defined(N) && N > 3\nbig\n#endif\n#if B != 0 && 10 / B > 1\ndivided\n#endif\n\
+         #if B == 0 || 10 / B > 1\nguarded\n#endif\nend\n",
+    );
+
+    let out = run_textpp(&["-DB=0", input.to_str().unwrap()]);
+
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "guarded\nend\n");
+}
+
+#[test]
+fn brace_dollar_form_expands_variables() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "Hi ${NAME}, that is $5.00.\n${NAME}${NAME} ${ not a name } $$NAME$$\n$${NAME}$\n[${MISSING}]\n",
+    );
+
+    let out = run_textpp(&["-DNAME=Ann", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "Hi Ann, that is $5.00.\nAnnAnn ${ not a name } Ann\n$Ann$\n[]\n"
+    );
+}
+
+#[test]
+fn dollar_vars_use_fallback_only_when_undefined() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#define EMPTY=\nby $$AUTHOR:-anon$$, [$$EMPTY:-none$$], $$NAME:-x:-y$$\n",
+    );
+
+    let out = run_textpp(&["-DNAME=Ann", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "by anon, [], Ann\n");
+}
+
+#[test]
+fn include_path_uses_fallback_when_undefined() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&dir.join("part_en.md"), "english\n");
+    write_file(&dir.join("part_de.md"), "german\n");
+    write_file(&input, "#include \"part_##LANG:-en##.md\"\n");
+
+    let default = run_textpp(&[input.to_str().unwrap()]);
+    let german = run_textpp(&["-DLANG=de", input.to_str().unwrap()]);
+
+    assert_eq!(String::from_utf8_lossy(&default.stdout), "english\n");
+    assert_eq!(String::from_utf8_lossy(&german.stdout), "german\n");
+}
+
+#[test]
+fn expand_recursive_expands_nested_variables() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "result: $$A$$\n");
+
+    let plain = run_textpp(&["-DA=$$B$$", "-DB=done", input.to_str().unwrap()]);
+    let recursive = run_textpp(&[
+        "--expand-recursive",
+        "-DA=$$B$$",
+        "-DB=done",
+        input.to_str().unwrap(),
+    ]);
+
+    assert_eq!(String::from_utf8_lossy(&plain.stdout), "result: $$B$$\n");
+    assert!(recursive.status.success());
+    assert_eq!(String::from_utf8_lossy(&recursive.stdout), "result: done\n");
+}
+
+#[test]
+fn expand_recursive_keeps_escaped_delimiters_literal() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "lit \\$$X\\$$ end $$A$$\n");
+
+    let plain = run_textpp(&["-DX=oops", "-DA=$$X$$", input.to_str().unwrap()]);
+    let recursive = run_textpp(&[
+        "--expand-recursive",
+        "-DX=oops",
+        "-DA=$$X$$",
+        input.to_str().unwrap(),
+    ]);
+
+    assert_eq!(String::from_utf8_lossy(&plain.stdout), "lit $$X$$ end $$X$$\n");
+    assert!(recursive.status.success());
+    assert_eq!(String::from_utf8_lossy(&recursive.stdout), "lit $$X$$ end oops\n");
+}
+
+#[test]
+fn expand_recursive_reports_non_converging_expansion() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "first\n$$A$$\n");
+
+    let out = run_textpp(&["--expand-recursive", "-DA=x$$A$$", input.to_str().unwrap()]);
+
+    assert_eq!(out.status.code(), Some(10));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("input.md:2: variable expansion did not converge"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn trace_logs_directives_without_changing_output() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#define VERSION 3\n#if VERSION >= 3\nnew\n#else\nold\n#endif\n$$VERSION$$\n",
+    );
+
+    let plain = run_textpp(&[input.to_str().unwrap()]);
+    let traced = run_textpp(&["--trace", input.to_str().unwrap()]);
+
+    assert!(traced.status.success());
+    assert_eq!(traced.stdout, plain.stdout);
+    let stderr = String::from_utf8_lossy(&traced.stderr);
+    assert!(stderr.contains("input.md:2 #if VERSION >= 3 => true (active)"), "{stderr}");
+    assert!(stderr.contains("input.md:4 #else => false (inactive)"), "{stderr}");
+    assert!(stderr.contains("input.md:6 #endif"), "{stderr}");
+    assert!(plain.stderr.is_empty());
+}
+
+#[test]
+fn dump_defines_prints_final_symbol_table_to_stderr() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#define LOCAL here\n#undef GONE\n$$MODE$$ $$LOCAL$$\n");
+
+    let plain = run_textpp(&["-DMODE=ci", "-DGONE=1", input.to_str().unwrap()]);
+    let dumped = run_textpp(&["--dump-defines", "-DMODE=ci", "-DGONE=1", input.to_str().unwrap()]);
+
+    assert!(dumped.status.success());
+    assert_eq!(dumped.stdout, plain.stdout);
+    assert_eq!(
+        String::from_utf8_lossy(&dumped.stderr),
+        "GONE <undefined>\nLOCAL=here\nMODE=ci\n"
+    );
+}
+
+#[test]
+fn list_includes_prints_reached_includes_only() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&dir.join("always.md"), "#include \"nested.md\"\n");
+    write_file(&dir.join("nested.md"), "nested\n");
+    write_file(&dir.join("extra.md"), "extra\n");
+    write_file(
+        &input,
+        "#include \"always.md\"\n#if EXTRA\n#include \"extra.md\"\n#endif\n#include \"always.md\"\n",
+    );
+
+    let plain = run_textpp(&["--list-includes", input.to_str().unwrap()]);
+    let extra = run_textpp(&["-M", "-DEXTRA=1", input.to_str().unwrap()]);
+
+    let always = dir.join("always.md");
+    let nested = dir.join("nested.md");
+    let extra_md = dir.join("extra.md");
+    assert!(plain.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&plain.stdout),
+        format!("{}\n{}\n", always.display(), nested.display())
+    );
+    assert!(extra.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&extra.stdout),
+        format!("{}\n{}\n{}\n", always.display(), nested.display(), extra_md.display())
+    );
+}
+
+#[test]
+fn backslash_escapes_double_dollar() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "price is \\$$5\n\\$$NAME\\$$ is $$NAME$$\nC:\\dir \\$5\n");
+
+    let out = run_textpp(&["-DNAME=Bob", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "price is $$5\n$$NAME$$ is Bob\nC:\\dir \\$5\n"
+    );
+}
+
+#[test]
+fn backslash_escapes_double_hash_in_include_path() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&dir.join("a##b.md"), "literal\n");
+    write_file(&input, "#include \"a\\##b.md\"\n#include \"a\\#\\#b.md\"\n");
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "literal\nliteral\n");
+}
+
+#[test]
+fn exec_defines_trimmed_command_output_only_when_allowed() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#exec GREETING = echo  hello   world\n[$$GREETING$$]\n");
+
+    let allowed = run_textpp(&["--allow-exec", input.to_str().unwrap()]);
+    let denied = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(allowed.status.success());
+    assert_eq!(String::from_utf8_lossy(&allowed.stdout), "[hello world]\n");
+    assert!(!denied.status.success());
+    let stderr = String::from_utf8_lossy(&denied.stderr);
+    assert!(stderr.contains("input.md:1: #exec is disabled; pass --allow-exec"), "{stderr}");
+}
+
+#[test]
+fn exec_fails_on_non_zero_exit() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "#exec X = false\n");
+
+    let out = run_textpp(&["--allow-exec", input.to_str().unwrap()]);
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("input.md:1: command 'false' failed"), "{stderr}");
+}
+
+#[test]
+fn list_expands_with_joiner_or_space() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#list ITEMS = a, b, c\n$$ITEMS|; $$\n$$ITEMS$$\n#define ITEMS x\n$$ITEMS|; $$\n",
+    );
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "a; b; c\na b c\nx\n");
+}
+
+#[test]
+fn multiple_inputs_are_concatenated_with_shared_defines() {
+    let dir = temp_dir();
+    let first = dir.join("01-intro.md");
+    let second = dir.join("02-body.md");
+    write_file(&first, "#define CHAPTER 1\nIntro by $$AUTHOR$$\n");
+    write_file(&second, "#eval NEXT = CHAPTER + 1\nChapter $$NEXT$$ by $$AUTHOR$$\n");
+
+    let out = run_textpp(&["-DAUTHOR=Ann", first.to_str().unwrap(), second.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "Intro by Ann\nChapter 2 by Ann\n"
+    );
+}
+
+#[test]
+fn line_prefix_and_suffix_wrap_every_output_line() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&dir.join("inc.md"), "included\n");
+    write_file(
+        &input,
+        "#capture BLOCK\nfirst\nsecond\n#endcapture\ntitle\n#include \"inc.md\"\n\n$$BLOCK$$\nlast",
+    );
+
+    let out = run_textpp(&["--line-prefix", "> ", "--line-suffix", " <", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "> title <\n> included <\n>  <\n> first <\n> second <\n> last <"
+    );
+}
+
+#[test]
+fn defines_file_is_applied_in_command_line_order() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    let defines = dir.join("ci.env");
+    write_file(
+        &defines,
+        "# CI settings\nMODE=release\n\nNAME = \"Build Bot\"\nFEATURE\nEARLY=file\n",
+    );
+    write_file(&input, "$$MODE$$ $$NAME$$ $$FEATURE$$ $$EARLY$$\n");
+
+    let out = run_textpp(&[
+        "-DEARLY=flag",
+        "--defines-file",
+        defines.to_str().unwrap(),
+        "-DMODE=debug",
+        input.to_str().unwrap(),
+    ]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "debug Build Bot TRUE file\n"
+    );
+}
+
+#[test]
+fn defines_file_keeps_empty_values_defined() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    let defines = dir.join("empty.env");
+    write_file(&defines, "EMPTY=\nQUOTED=\"\"\n");
+    write_file(
+        &input,
+        "#ifdef EMPTY\nempty defined\n#endif\n#ifdef QUOTED\nquoted defined\n#endif\n[$$EMPTY$$]\n",
+    );
+
+    let out = run_textpp(&["--defines-file", defines.to_str().unwrap(), input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "empty defined\nquoted defined\n[]\n"
+    );
+}
+
+#[test]
+fn defines_file_reports_bad_lines_and_missing_files() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    let defines = dir.join("bad.env");
+    write_file(&defines, "OK=1\nnot a key=2\n");
+    write_file(&input, "text\n");
+
+    let bad = run_textpp(&["--defines-file", defines.to_str().unwrap(), input.to_str().unwrap()]);
+    let missing = dir.join("missing.env");
+    let missing = run_textpp(&["--defines-file", missing.to_str().unwrap(), input.to_str().unwrap()]);
+
+    assert_eq!(bad.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&bad.stderr).contains("bad.env:2: invalid define"));
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&missing.stderr).contains("cannot read defines file"));
+}
+
+#[test]
+fn import_env_defines_prefixed_variables_below_cli_defines() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "$$NAME$$ $$ROLE$$ [$$OTHER_NAME$$]\n");
+
+    let out = run_textpp_with_env(
+        &["-DROLE=admin", "--import-env", "TEXTPP_TEST_", input.to_str().unwrap()],
+        &[
+            ("TEXTPP_TEST_NAME", "Bob"),
+            ("TEXTPP_TEST_ROLE", "guest"),
+            ("OTHER_NAME", "ignored"),
+        ],
+    );
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "Bob admin []\n");
+}
+
+#[test]
+fn if_concatenation_binds_tighter_than_comparison() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#if PREFIX + \"_x\" == \"foo_x\"\nconcat\n#endif\n#if PREFIX + \"_x\" == TARGET && !MISSING || MISSING\ncombined\n#endif\n\
+         #if len(PREFIX + \"_x\") == 5 && len(MISSING) == 0\nlength\n#endif\n",
+    );
+
+    let out = run_textpp(&["-DPREFIX=foo", "-DTARGET=foo_x", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "concat\ncombined\nlength\n");
+}
+
+#[test]
+fn warn_undefined_reports_each_undefined_variable() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "a [$$NAME$$]\nb [${OTHER}] [$$NAME:-x$$]\n");
+
+    let out = run_textpp(&["--warn-undefined", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "a []\nb [] [x]\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("input.md:1: warning: undefined variable 'NAME'"), "{stderr}");
+    assert!(stderr.contains("input.md:2: warning: undefined variable 'OTHER'"), "{stderr}");
+    assert_eq!(stderr.lines().count(), 2, "{stderr}");
+}
+
+#[test]
+fn error_undefined_fails_on_undefined_variable() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&input, "fine $$DEFINED$$\ntypo $$DEFIEND$$\n");
+
+    let out = run_textpp(&["--error-undefined", "-DDEFINED=1", input.to_str().unwrap()]);
+    let silent = run_textpp(&["-DDEFINED=1", input.to_str().unwrap()]);
+
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("input.md:2: undefined variable 'DEFIEND'"), "{stderr}");
+    assert!(silent.status.success());
+    assert!(silent.stderr.is_empty());
+}
+
+#[test]
+fn output_file_is_not_written_when_processing_fails_midway() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    let output = dir.join("streamed_out.md");
+    write_file(&output, "previous\n");
+    write_file(&input, "before\n#if X between 1 and 2\n#endif\n");
+
+    let out = run_textpp(&["-DX=many", "-o", output.to_str().unwrap(), input.to_str().unwrap()]);
+
+    assert_eq!(out.status.code(), Some(10));
+    assert_eq!(fs::read_to_string(&output).unwrap(), "previous\n");
+}
+
+#[test]
+fn missing_final_newline_is_kept_but_includes_still_end_lines() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(&dir.join("part.md"), "part");
+    write_file(&input, "#include \"part.md\"\nlast $$X$$");
+
+    let lf = run_textpp(&["-DX=1", input.to_str().unwrap()]);
+    let crlf = run_textpp(&["--line-endings=crlf", "-DX=1", input.to_str().unwrap()]);
+
+    assert!(lf.status.success());
+    assert_eq!(String::from_utf8_lossy(&lf.stdout), "part\nlast 1");
+    assert_eq!(String::from_utf8_lossy(&crlf.stdout), "part\r\nlast 1");
+}
+
+#[test]
+fn error_directive_stops_processing_only_when_active() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#ifndef PLATFORM\n#error \"no platform given\"\n#endif\n#if PLATFORM == \"beos\"\n#error \"unsupported platform $$PLATFORM$$\"\n#endif\nok on $$PLATFORM$$\n",
+    );
+
+    let failed = run_textpp(&["-DPLATFORM=beos", input.to_str().unwrap()]);
+    let passed = run_textpp(&["-DPLATFORM=linux", input.to_str().unwrap()]);
+
+    assert_eq!(failed.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    assert!(stderr.contains("input.md:5: error: unsupported platform beos"), "{stderr}");
+    assert!(passed.status.success());
+    assert!(passed.stderr.is_empty());
+    assert_eq!(String::from_utf8_lossy(&passed.stdout), "ok on linux\n");
+}
+
+#[test]
+fn warning_directive_expands_variables_when_active() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#warning \"building for $$PLATFORM$$\"\n#ifdef NEVER\n#warning hidden\n#endif\ntext\n",
+    );
+
+    let out = run_textpp(&["-DPLATFORM=linux", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "text\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("input.md:1: warning: building for linux"), "{stderr}");
+    assert!(!stderr.contains("hidden"), "{stderr}");
+}
+
+#[test]
+fn directive_messages_expand_dollar_vars_but_not_hash_vars() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    write_file(
+        &input,
+        "#warning \"value is $$X$$\"\n#warning \"${X} or $$MISSING:-none$$, not ##X##\"\ntext\n",
+    );
+
+    let out = run_textpp(&["-DX=42", input.to_str().unwrap()]);
+    let strict = run_textpp(&["--error-undefined", input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "text\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("input.md:1: warning: value is 42"), "{stderr}");
+    assert!(stderr.contains("input.md:2: warning: 42 or none, not ##X##"), "{stderr}");
+    assert!(!strict.status.success());
+    let stderr = String::from_utf8_lossy(&strict.stderr);
+    assert!(stderr.contains("input.md:1: undefined variable 'X'"), "{stderr}");
+}
+
+#[test]
+fn pragma_once_emits_a_guarded_file_only_once_per_input() {
+    let dir = temp_dir();
+    let input = dir.join("input.md");
+    let second = dir.join("second.md");
+    write_file(&dir.join("header.md"), "#pragma once\nheader\n");
+    write_file(&dir.join("chapter.md"), "#include \"header.md\"\nchapter\n");
+    write_file(&input, "#include \"header.md\"\n#include \"chapter.md\"\n#include \"header.md\"\nend\n");
+    write_file(&second, "#include \"header.md\"\n");
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+    let batch = run_textpp(&[
+        "--in-place-suffix",
+        ".once",
+        input.to_str().unwrap(),
+        second.to_str().unwrap(),
+    ]);
+
+    assert!(out.status.success());
+    assert!(out.stderr.is_empty());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "header\nchapter\nend\n");
+    assert!(batch.status.success());
+    let second_out = fs::read_to_string(dir.join("second.md.once")).unwrap();
+    assert_eq!(second_out, "header\n");
+}
+
+#[test]
+fn include_base_overrides_including_file_directory() {
+    let dir = temp_dir();
+    write_file(&dir.join("root/shared/footer.md"), "footer\n");
+    write_file(&dir.join("root/docs/part.md"), "part\n#include \"shared/footer.md\"\n");
+    let input = dir.join("root/docs/input.md");
+    write_file(&input, "#include \"docs/part.md\"\n");
+    let base = dir.join("root");
+
+    let out = run_textpp(&[input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "");
+
+    let out = run_textpp(&["--include-base", base.to_str().unwrap(), input.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "part\nfooter\n");
+}
+
+#[test]
+fn include_prefers_base_dir_over_include_dirs() {
+    let dir = temp_dir();
+    write_file(&dir.join("search/shared/note.md"), "note from -I\n");
+    write_file(&dir.join("search/shared/only.md"), "only in -I\n");
+    write_file(&dir.join("search/doc/note.md"), "local note\n");
+    let input = dir.join("search/doc/input.md");
+    write_file(&input, "#include \"note.md\"\n#include \"only.md\"\n");
+    let shared = dir.join("search/shared");
+
+    let out = run_textpp(&["-I", shared.to_str().unwrap(), input.to_str().unwrap()]);
+
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "local note\nonly in -I\n");
+}